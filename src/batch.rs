@@ -0,0 +1,309 @@
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+use serde::{Serialize, Deserialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::Config;
+use crate::dataset::{self, Dataset, ResizePolicy};
+use crate::error::{Error, Result};
+use crate::features::{self, FeatureFile, FeatureSet};
+use crate::{npy, opencv};
+
+// the formats a batch can write each image's features in
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum OutputFormat {
+    Json,
+    Binary,
+    Npz,
+    OpenCv
+}
+
+impl OutputFormat {
+    // parse "json", "binary", "npz" or "opencv"
+    pub fn parse(s:&str) -> Result<OutputFormat> {
+        match s {
+            "json" => Ok(OutputFormat::Json),
+            "binary" => Ok(OutputFormat::Binary),
+            "npz" => Ok(OutputFormat::Npz),
+            "opencv" => Ok(OutputFormat::OpenCv),
+            _ => Err(Error::Parse(format!("unknown output format {}", s)))
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Json => "json",
+            OutputFormat::Binary => "orbf",
+            OutputFormat::Npz => "npz",
+            OutputFormat::OpenCv => "yml"
+        }
+    }
+
+    pub fn save(&self, filename:&str, features:&FeatureSet) -> Result<()> {
+        match self {
+            OutputFormat::Json => features.save(filename),
+            OutputFormat::Binary => features.save_binary(filename),
+            OutputFormat::Npz => npy::save_npz(filename, &features.features),
+            OutputFormat::OpenCv => opencv::save_yaml(filename, &features.features)
+        }
+    }
+
+    // whether the format records the config hash itself, rather than in a
+    // hash file beside the output
+    fn holds_hash(&self) -> bool {
+        matches!(self, OutputFormat::Json | OutputFormat::Binary)
+    }
+
+    // the config hash a saved output was found with
+    fn config_hash(&self, output:&Path) -> Result<String> {
+        let filename = path_str(output)?;
+        Ok(match self {
+            OutputFormat::Json => FeatureSet::load(filename)?.config_hash,
+            OutputFormat::Binary => FeatureFile::open(filename)?.config_hash(),
+            OutputFormat::Npz | OutputFormat::OpenCv => fs::read_to_string(hash_path(output))?.trim().to_string()
+        })
+    }
+}
+
+fn path_str(path:&Path) -> Result<&str> {
+    path.to_str().ok_or_else(|| Error::Format(format!("{} isn't a valid file name", path.display())))
+}
+
+// the file holding the config hash of an output in a format without one, e.g. a.png.npz.hash
+fn hash_path(output:&Path) -> PathBuf {
+    let mut name = output.as_os_str().to_owned();
+    name.push(".hash");
+    PathBuf::from(name)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BatchOptions {
+    pub format: OutputFormat,
+    pub resize: ResizePolicy,
+    // the most images processed at once, 0 for one per core. Without the
+    // parallel feature images are processed one at a time.
+    pub threads: usize,
+    // write every output, even those that are up to date
+    pub force: bool
+}
+
+impl Default for BatchOptions {
+    fn default() -> BatchOptions {
+        BatchOptions {
+            format: OutputFormat::Json,
+            resize: ResizePolicy::Original,
+            threads: 0,
+            force: false
+        }
+    }
+}
+
+// what became of one image
+#[derive(Debug)]
+pub enum Outcome {
+    // written with this many features
+    Written(usize),
+    UpToDate,
+    Failed(Error)
+}
+
+#[derive(Debug)]
+pub struct BatchItem {
+    pub input: PathBuf,
+    pub output: PathBuf,
+    pub outcome: Outcome
+}
+
+// the outcome of every image, in the order of the dataset
+#[derive(Debug, Default)]
+pub struct BatchReport {
+    pub items: Vec<BatchItem>
+}
+
+impl BatchReport {
+    pub fn written(&self) -> usize {
+        self.items.iter().filter(|i| matches!(i.outcome, Outcome::Written(_))).count()
+    }
+
+    pub fn up_to_date(&self) -> usize {
+        self.items.iter().filter(|i| matches!(i.outcome, Outcome::UpToDate)).count()
+    }
+
+    pub fn failures(&self) -> impl Iterator<Item = &BatchItem> {
+        self.items.iter().filter(|i| matches!(i.outcome, Outcome::Failed(_)))
+    }
+}
+
+// the output for an image, its path relative to root kept under output with the
+// format's extension added, e.g. root/a/b.png -> output/a/b.png.json
+pub fn output_path(input:&Path, root:&Path, output:&Path, format:OutputFormat) -> PathBuf {
+    let relative = input.strip_prefix(root).unwrap_or_else(|_| input.file_name().map_or(input, Path::new));
+    let mut name = relative.as_os_str().to_owned();
+    name.push(".");
+    name.push(format.extension());
+    output.join(name)
+}
+
+fn modified(path:&Path) -> Result<std::time::SystemTime> {
+    Ok(fs::metadata(path)?.modified()?)
+}
+
+// an output is up to date if it's newer than its image and was found with
+// the same config and resize policy
+fn is_up_to_date(input:&Path, output:&Path, format:OutputFormat, hash:&str) -> bool {
+    let newer = match (modified(input), modified(output)) {
+        (Ok(i), Ok(o)) => o >= i,
+        _ => false
+    };
+    newer && format.config_hash(output).map_or(false, |h| h == hash)
+}
+
+// features are found in the resized image but saved at the resolution of
+// the original, as its width and height
+fn extract_image(input:&Path, output:&Path, config:&Config, options:&BatchOptions, hash:&str) -> Result<usize> {
+    let image = dataset::load_image(input, options.resize)?;
+    let mut features = FeatureSet::detect(&image, config)?;
+    let (w, h) = image::image_dimensions(input)?;
+    let scale = (w as f32 / image.width() as f32, h as f32 / image.height() as f32);
+    for k in features.features.iter_mut() {
+        k.x *= scale.0;
+        k.y *= scale.1;
+    }
+    features.width = w;
+    features.height = h;
+    features.image = input.to_string_lossy().into_owned();
+    features.config_hash = hash.to_string();
+    if let Some(dir) = output.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    // a stale hash file mustn't vouch for the output while it's replaced
+    let hash_file = hash_path(output);
+    if hash_file.exists() {
+        fs::remove_file(&hash_file)?;
+    }
+    // written alongside then renamed so an interrupted run never leaves a
+    // partial file that looks up to date
+    let mut partial = output.as_os_str().to_owned();
+    partial.push(".tmp");
+    let partial = PathBuf::from(partial);
+    options.format.save(path_str(&partial)?, &features)?;
+    fs::rename(&partial, output)?;
+    if !options.format.holds_hash() {
+        fs::write(&hash_file, hash)?;
+    }
+    Ok(features.features.len())
+}
+
+// find the features of each image on a pool of options.threads workers, writing
+// a file for each under output. done is called as each image finishes, in
+// whatever order they finish, and failures are reported rather than stopping the run.
+pub fn extract<F>(images:&Dataset, root:&Path, output:&Path, config:&Config,
+                  options:&BatchOptions, done:F) -> Result<BatchReport>
+where F:Fn(&BatchItem) + Sync {
+    config.validate()?;
+    let hash = features::resized_config_hash(config, options.resize)?;
+    let item = |input:&PathBuf| {
+        let output = output_path(input, root, output, options.format);
+        let outcome = if !options.force && is_up_to_date(input, &output, options.format, &hash) {
+            Outcome::UpToDate
+        } else {
+            match extract_image(input, &output, config, options, &hash) {
+                Ok(n) => Outcome::Written(n),
+                Err(e) => Outcome::Failed(e)
+            }
+        };
+        let item = BatchItem {
+            input: input.clone(),
+            output: output,
+            outcome: outcome
+        };
+        done(&item);
+        item
+    };
+    #[cfg(feature = "parallel")]
+    let items = rayon::ThreadPoolBuilder::new()
+        .num_threads(options.threads)
+        .build()
+        .map_err(|e| Error::Format(e.to_string()))?
+        .install(|| images.paths.par_iter().map(item).collect());
+    #[cfg(not(feature = "parallel"))]
+    let items = images.paths.iter().map(item).collect();
+    Ok(BatchReport { items: items })
+}
+
+// extract the features of the images with one of the extensions in dir,
+// and its subfolders if recursive
+pub fn extract_dir<F>(dir:&Path, recursive:bool, extensions:&[String], output:&Path, config:&Config,
+                      options:&BatchOptions, done:F) -> Result<BatchReport>
+where F:Fn(&BatchItem) + Sync {
+    let images = Dataset::from_dirs(&[dir], recursive, extensions)?;
+    extract(&images, dir, output, config, options, done)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_batch() {
+        let dir = std::env::temp_dir().join("image_processing_test_batch");
+        let _ = fs::remove_dir_all(&dir);
+        let (images, output) = (dir.join("images"), dir.join("features"));
+        fs::create_dir_all(images.join("sub")).unwrap();
+        let image = crate::checkerboard();
+        image.save(images.join("a.png")).unwrap();
+        image.save(images.join("sub/b.png")).unwrap();
+        fs::write(images.join("broken.png"), b"not a png").unwrap();
+
+        let png = vec!["png".to_string()];
+        let options = BatchOptions { format: OutputFormat::Binary, threads: 2, ..BatchOptions::default() };
+        let config = Config::default();
+        let count = AtomicUsize::new(0);
+        let report = extract_dir(&images, true, &png, &output, &config, &options,
+                                 |_item| { count.fetch_add(1, Ordering::SeqCst); }).unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+        assert_eq!((report.written(), report.up_to_date()), (2, 0));
+        let failed:Vec<&BatchItem> = report.failures().collect();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].input, images.join("broken.png"));
+        let b = output.join("sub/b.png.orbf");
+        assert_eq!(report.items[2].output, b);
+        assert!(!FeatureFile::open(b.to_str().unwrap()).unwrap().is_empty());
+
+        // a second run only retries the failure, unless the config changes
+        let report = extract_dir(&images, true, &png, &output, &config, &options, |_item| {}).unwrap();
+        assert_eq!((report.written(), report.up_to_date(), report.failures().count()), (0, 2, 1));
+        let upright = Config { upright: true, ..Config::default() };
+        let report = extract_dir(&images, true, &png, &output, &upright, &options, |_item| {}).unwrap();
+        assert_eq!(report.written(), 2);
+
+        // keypoints found in a resized image are saved at the original resolution,
+        // and changing the resize policy invalidates them
+        let double = BatchOptions { format: OutputFormat::Json, resize: ResizePolicy::Scale(2.0), ..options.clone() };
+        let report = extract_dir(&images, true, &png, &output, &config, &double, |_item| {}).unwrap();
+        assert_eq!(report.written(), 2);
+        let set = FeatureSet::load(output.join("a.png.json").to_str().unwrap()).unwrap();
+        assert_eq!((set.width, set.height), (128, 96));
+        assert!(!set.features.is_empty());
+        assert!(set.features.iter().all(|k| k.x < 128.0 && k.y < 96.0));
+        assert!(!set.matches_config(&config));
+        let larger = BatchOptions { resize: ResizePolicy::Scale(1.5), ..double };
+        let report = extract_dir(&images, true, &png, &output, &config, &larger, |_item| {}).unwrap();
+        assert_eq!(report.written(), 2);
+
+        // formats without a hash of their own keep one beside the output
+        let npz = BatchOptions { format: OutputFormat::Npz, ..options.clone() };
+        let report = extract_dir(&images, true, &png, &output, &config, &npz, |_item| {}).unwrap();
+        assert_eq!(report.written(), 2);
+        assert!(output.join("a.png.npz.hash").exists());
+        let report = extract_dir(&images, true, &png, &output, &config, &npz, |_item| {}).unwrap();
+        assert_eq!(report.up_to_date(), 2);
+        let report = extract_dir(&images, true, &png, &output, &upright, &npz, |_item| {}).unwrap();
+        assert_eq!(report.written(), 2);
+
+        assert_eq!(OutputFormat::parse("opencv").unwrap(), OutputFormat::OpenCv);
+        assert!(OutputFormat::parse("csv").is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,163 @@
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use rand::Rng;
+use crate::{Config, Corner, find_matches, match_indices, matched_points};
+use crate::error::Result;
+use crate::opencv::keypoint_size;
+use crate::registration::{self, PointPair, RansacParams};
+
+// COLMAP's text formats for importing custom features and matches, as read by
+// its feature_importer and matches_importer
+
+// COLMAP only imports descriptors of this length
+pub const DESCRIPTOR_DIMENSIONS:usize = 128;
+
+// the matches between two images named as COLMAP names them, relative to its
+// image folder, as (index in a, index in b)
+#[derive(Clone, Debug, PartialEq)]
+pub struct ImagePair {
+    pub a: String,
+    pub b: String,
+    pub matches: Vec<(usize, usize)>
+}
+
+// a line of the number of keypoints and descriptor length, then x y scale
+// orientation and descriptor for each keypoint. COLMAP puts the centre of the
+// top left pixel at (0.5, 0.5), and scale takes positions from the image the
+// corners were found in to the image COLMAP reads. Each descriptor bit is
+// written as 0 or 255 so L2 distance follows hamming distance. Corners without
+// descriptors are left out, so keypoints are numbered as keypoint_indices gives.
+pub fn write_keypoints<W:Write>(w:&mut W, corners:&[Corner], scale:(f32, f32)) -> Result<()> {
+    writeln!(w, "{} {}", corners.iter().filter(|c| c.descriptor.is_some()).count(), DESCRIPTOR_DIMENSIONS)?;
+    for c in corners.iter() {
+        let descriptor = match c.descriptor {
+            Some(d) => d,
+            None => continue
+        };
+        let (x, y) = c.position();
+        write!(w, "{} {} {} {}", (x + 0.5) * scale.0, (y + 0.5) * scale.1,
+               keypoint_size(c.level) * 0.5 * scale.0, c.angle)?;
+        for i in 0..DESCRIPTOR_DIMENSIONS {
+            write!(w, " {}", if descriptor & (1u128 << i) != 0 { 255 } else { 0 })?;
+        }
+        writeln!(w)?;
+    }
+    Ok(())
+}
+
+// the index of each corner in its keypoint file, none for corners without descriptors
+pub fn keypoint_indices(corners:&[Corner]) -> Vec<Option<usize>> {
+    let mut n = 0;
+    corners.iter()
+        .map(|c| c.descriptor.map(|_| {
+            n += 1;
+            n - 1
+        }))
+        .collect()
+}
+
+pub fn save_keypoints(filename:&Path, corners:&[Corner], scale:(f32, f32)) -> Result<()> {
+    if let Some(dir) = filename.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = std::io::BufWriter::new(fs::File::create(filename)?);
+    write_keypoints(&mut file, corners, scale)?;
+    file.flush()?;
+    Ok(())
+}
+
+// each pair of the images to match, or with a window only those at most that
+// many places apart in order, as for frames of a video
+pub fn image_pairs(n:usize, window:Option<usize>) -> Vec<(usize, usize)> {
+    let window = window.unwrap_or(n);
+    (0..n)
+        .flat_map(|i| (i + 1..n.min(i + 1 + window)).map(move |j| (i, j)))
+        .collect()
+}
+
+// the matches of b to a that agree with the homography registering b to a,
+// none if the images can't be registered. Each feature of a is used once, and
+// matches are numbered as the keypoint files number the features.
+pub fn verified_matches<R:Rng>(a:&Vec<Corner>, b:&Vec<Corner>, config:&Config,
+                               params:&RansacParams, rng:&mut R) -> Result<Vec<(usize, usize)>> {
+    let matches = find_matches(a, b, config)?;
+    let indices = match_indices(a, &matches);
+    let pairs = matched_points(b, &matches);
+    let points:Vec<PointPair> = pairs.iter().map(|p| p.1).collect();
+    let inliers = registration::register(&points, params, rng).map_or(Vec::new(), |r| r.inliers);
+    let (keypoints_a, keypoints_b) = (keypoint_indices(a), keypoint_indices(b));
+    let mut used = vec![false; a.len()];
+    Ok(inliers.iter()
+        .filter_map(|&i| {
+            let j = pairs[i].0;
+            let i = indices[j]?;
+            let (ka, kb) = (keypoints_a[i]?, keypoints_b[j]?);
+            if used[i] {
+                return None;
+            }
+            used[i] = true;
+            Some((ka, kb))
+        })
+        .collect())
+}
+
+// the names of each pair then a line per match, pairs separated by a blank
+// line. Pairs without matches are left out.
+pub fn write_matches<W:Write>(w:&mut W, pairs:&[ImagePair]) -> Result<()> {
+    for pair in pairs.iter().filter(|p| !p.matches.is_empty()) {
+        writeln!(w, "{} {}", pair.a, pair.b)?;
+        for (i, j) in pair.matches.iter() {
+            writeln!(w, "{} {}", i, j)?;
+        }
+        writeln!(w)?;
+    }
+    Ok(())
+}
+
+pub fn save_matches(filename:&Path, pairs:&[ImagePair]) -> Result<()> {
+    let mut file = std::io::BufWriter::new(fs::File::create(filename)?);
+    write_matches(&mut file, pairs)?;
+    file.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use imageproc::corners;
+
+    #[test]
+    fn test_colmap() {
+        let corner = |descriptor| Corner {
+            corner: corners::Corner::new(10, 20, 5.0),
+            angle: 0.5,
+            orientation_confidence: 1.0,
+            descriptor: descriptor,
+            level: 1
+        };
+        // undescribed corners are left out and the rest numbered without them
+        let corners = [corner(None), corner(Some(0b101)), corner(None)];
+        assert_eq!(keypoint_indices(&corners), vec![None, Some(0), None]);
+        let mut text = Vec::new();
+        write_keypoints(&mut text, &corners, (2.0, 2.0)).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        let lines:Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0], "1 128");
+        let values:Vec<&str> = lines[1].split(' ').collect();
+        assert_eq!(values.len(), 4 + DESCRIPTOR_DIMENSIONS);
+        assert_eq!(&values[..7], &["41", "81", "62", "0.5", "255", "0", "255"]);
+
+        assert_eq!(image_pairs(3, None), vec![(0, 1), (0, 2), (1, 2)]);
+        assert_eq!(image_pairs(4, Some(1)), vec![(0, 1), (1, 2), (2, 3)]);
+
+        let pairs = vec![
+            ImagePair { a: "a.png".to_string(), b: "b.png".to_string(), matches: vec![(0, 3), (2, 1)] },
+            ImagePair { a: "a.png".to_string(), b: "c.png".to_string(), matches: vec![] }
+        ];
+        let mut text = Vec::new();
+        write_matches(&mut text, &pairs).unwrap();
+        assert_eq!(String::from_utf8(text).unwrap(), "a.png b.png\n0 3\n2 1\n\n");
+    }
+}
@@ -0,0 +1,193 @@
+use image::{imageops, GrayImage};
+use serde::{Serialize, Deserialize};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::error::{Error, Result};
+
+// how training images are resized before features are found
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ResizePolicy {
+    // use images as they are
+    Original,
+    // scale to the given width, keeping the aspect ratio
+    Width(u32),
+    // scale down so neither side exceeds the given size
+    MaxDimension(u32),
+    Scale(f32)
+}
+
+impl Default for ResizePolicy {
+    fn default() -> ResizePolicy {
+        // ~ 640x480
+        ResizePolicy::Width(640)
+    }
+}
+
+impl ResizePolicy {
+    // parse "original", "width:640", "max:800" or "scale:0.5"
+    pub fn parse(s:&str) -> Result<ResizePolicy> {
+        let mut parts = s.splitn(2, ':');
+        let name = parts.next().unwrap_or("");
+        let value = parts.next();
+        let policy = match (name, value) {
+            ("original", None) => ResizePolicy::Original,
+            ("width", Some(v)) => ResizePolicy::Width(v.parse()?),
+            ("max", Some(v)) => ResizePolicy::MaxDimension(v.parse()?),
+            ("scale", Some(v)) => ResizePolicy::Scale(v.parse()?),
+            _ => return Err(Error::Parse(format!("unknown resize policy {}", s)))
+        };
+        Ok(policy)
+    }
+
+    // the size an image of w x h is resized to
+    pub fn size(&self, w:u32, h:u32) -> (u32, u32) {
+        let scale = match *self {
+            ResizePolicy::Original => 1.0,
+            ResizePolicy::Width(width) => width as f32 / w as f32,
+            ResizePolicy::MaxDimension(max) => (max as f32 / w.max(h) as f32).min(1.0),
+            ResizePolicy::Scale(scale) => scale
+        };
+        (((w as f32 * scale).round() as u32).max(1), ((h as f32 * scale).round() as u32).max(1))
+    }
+
+    pub fn apply(&self, image:GrayImage) -> GrayImage {
+        let (w, h) = image.dimensions();
+        let (rw, rh) = self.size(w, h);
+        if (rw, rh) == (w, h) {
+            image
+        } else {
+            imageops::resize(&image, rw, rh, imageops::FilterType::CatmullRom)
+        }
+    }
+}
+
+// an ordered list of image files
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Dataset {
+    pub paths: Vec<PathBuf>
+}
+
+fn has_extension(path:&Path, extensions:&[String]) -> bool {
+    extensions.is_empty() || path.extension()
+        .and_then(|e| e.to_str())
+        .map_or(false, |e| extensions.iter().any(|x| x.eq_ignore_ascii_case(e)))
+}
+
+// symlinked files are included but symlinked folders aren't followed, so a
+// link back up the tree can't recurse forever
+fn walk(dir:&Path, recursive:bool, extensions:&[String], paths:&mut Vec<PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            if recursive {
+                walk(&path, recursive, extensions, paths)?;
+            }
+        } else if path.is_file() && has_extension(&path, extensions) {
+            paths.push(path);
+        }
+    }
+    Ok(())
+}
+
+impl Dataset {
+    // the files in dirs, optionally in their subfolders too, with one of the
+    // extensions or any extension if none are given
+    pub fn from_dirs<P:AsRef<Path>>(dirs:&[P], recursive:bool, extensions:&[String]) -> Result<Dataset> {
+        let mut paths = Vec::new();
+        for dir in dirs.iter() {
+            walk(dir.as_ref(), recursive, extensions, &mut paths)?;
+        }
+        // read_dir order is platform dependent, sort so runs are repeatable
+        paths.sort();
+        paths.dedup();
+        Ok(Dataset { paths: paths })
+    }
+
+    // a text file of image paths, one per line, relative to the list's folder
+    pub fn from_file_list(filename:&str) -> Result<Dataset> {
+        let base = Path::new(filename).parent().unwrap_or(Path::new(""));
+        let paths = fs::read_to_string(filename)?
+            .lines()
+            .map(|l| l.trim())
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(|l| base.join(l))
+            .collect();
+        Ok(Dataset { paths: paths })
+    }
+
+    // add the paths of other after these, keeping their order and leaving out
+    // any already here
+    pub fn append(&mut self, other:Dataset) {
+        let mut seen:HashSet<PathBuf> = self.paths.iter().cloned().collect();
+        for path in other.paths {
+            if seen.insert(path.clone()) {
+                self.paths.push(path);
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.paths.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.paths.is_empty()
+    }
+}
+
+// open an image as greyscale and resize it
+pub fn load_image(path:&Path, resize:ResizePolicy) -> Result<GrayImage> {
+    Ok(resize.apply(image::open(path)?.into_luma8()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resize_policy() {
+        assert_eq!(ResizePolicy::parse("original").unwrap(), ResizePolicy::Original);
+        assert_eq!(ResizePolicy::parse("width:320").unwrap(), ResizePolicy::Width(320));
+        assert!(ResizePolicy::parse("width").is_err());
+        assert!(ResizePolicy::parse("max:x").is_err());
+        assert_eq!(ResizePolicy::default().size(1280, 960), (640, 480));
+        assert_eq!(ResizePolicy::MaxDimension(800).size(1600, 1200), (800, 600));
+        assert_eq!(ResizePolicy::MaxDimension(800).size(400, 300), (400, 300));
+        assert_eq!(ResizePolicy::Scale(0.5).size(3, 3), (2, 2));
+    }
+
+    #[test]
+    fn test_dataset() {
+        let dir = std::env::temp_dir().join("image_processing_test_dataset");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        for name in ["b.png", "a.PNG", "notes.txt", "sub/c.png"].iter() {
+            fs::write(dir.join(name), b"").unwrap();
+        }
+        let png = vec!["png".to_string()];
+        let d = Dataset::from_dirs(&[&dir], false, &png).unwrap();
+        assert_eq!(d.paths, vec![dir.join("a.PNG"), dir.join("b.png")]);
+        let d = Dataset::from_dirs(&[&dir], true, &png).unwrap();
+        assert_eq!(d.len(), 3);
+        let d = Dataset::from_dirs(&[&dir], false, &[]).unwrap();
+        assert_eq!(d.len(), 3);
+
+        let list = dir.join("list.txt");
+        fs::write(&list, "# training images\nsub/c.png\n\nb.png\n").unwrap();
+        let d = Dataset::from_file_list(list.to_str().unwrap()).unwrap();
+        assert_eq!(d.paths, vec![dir.join("sub/c.png"), dir.join("b.png")]);
+        let mut all = Dataset::from_dirs(&[&dir], false, &png).unwrap();
+        all.append(d);
+        assert_eq!(all.paths, vec![dir.join("a.PNG"), dir.join("b.png"), dir.join("sub/c.png")]);
+
+        // a link back up the tree isn't followed
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(&dir, dir.join("sub/loop")).unwrap();
+            assert_eq!(Dataset::from_dirs(&[&dir], true, &png).unwrap().len(), 3);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
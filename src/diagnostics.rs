@@ -0,0 +1,196 @@
+use serde::{Serialize, Deserialize};
+use std::fs;
+use crate::error::Result;
+
+fn bit(d:u128, i:usize) -> bool {
+    (d >> i) & 1 == 1
+}
+
+// counts gathered from descriptors made with a test set of the given number
+// of bits, and from pairs of descriptors known to show the same point
+#[derive(Clone, Debug)]
+pub struct BitStatistics {
+    bits: usize,
+    samples: u32,
+    ones: Vec<u32>,
+    // agree[i][j] for j > i counts descriptors where bits i and j are equal
+    agree: Vec<Vec<u32>>,
+    matched: u32,
+    flips: Vec<u32>
+}
+
+impl BitStatistics {
+    pub fn new(bits:usize) -> BitStatistics {
+        BitStatistics {
+            bits: bits,
+            samples: 0,
+            ones: vec![0; bits],
+            agree: vec![vec![0; bits]; bits],
+            matched: 0,
+            flips: vec![0; bits]
+        }
+    }
+
+    pub fn bits(&self) -> usize {
+        self.bits
+    }
+
+    pub fn samples(&self) -> u32 {
+        self.samples
+    }
+
+    pub fn add(&mut self, d:u128) {
+        self.samples += 1;
+        for i in 0..self.bits {
+            let b = bit(d, i);
+            if b {
+                self.ones[i] += 1;
+            }
+            for j in (i + 1)..self.bits {
+                if bit(d, j) == b {
+                    self.agree[i][j] += 1;
+                }
+            }
+        }
+    }
+
+    // a and b describe the same point, such as a corner and its location in a
+    // warped copy of the image
+    pub fn add_matched(&mut self, a:u128, b:u128) {
+        self.matched += 1;
+        let flipped = a ^ b;
+        for i in 0..self.bits {
+            if bit(flipped, i) {
+                self.flips[i] += 1;
+            }
+        }
+    }
+
+    // the phi coefficient of bits i < j. both set on (ones_i + ones_j + agree - n) / 2
+    // descriptors, and a constant bit is uncorrelated with everything
+    fn phi(&self, i:usize, j:usize) -> f32 {
+        let n = self.samples.max(1) as f64;
+        let (pi, pj) = (self.ones[i] as f64 / n, self.ones[j] as f64 / n);
+        let both = (self.ones[i] as f64 + self.ones[j] as f64 + self.agree[i][j] as f64 - n) / (2.0 * n);
+        let variance = pi * (1.0 - pi) * pj * (1.0 - pj);
+        if variance <= 0.0 {
+            0.0
+        } else {
+            ((both - pi * pj) / variance.sqrt()) as f32
+        }
+    }
+
+    pub fn diagnostics(&self) -> BitDiagnostics {
+        let n = self.samples.max(1) as f32;
+        let mean:Vec<f32> = self.ones.iter().map(|&c| c as f32 / n).collect();
+        let entropy = mean.iter()
+            .map(|&p| if p <= 0.0 || p >= 1.0 { 0.0 } else {
+                -p * p.log2() - (1.0 - p) * (1.0 - p).log2()
+            })
+            .collect();
+        let correlation = (0..self.bits)
+            .map(|i| (0..self.bits)
+                 .map(|j| match i.cmp(&j) {
+                     std::cmp::Ordering::Equal => 1.0,
+                     std::cmp::Ordering::Less => self.phi(i, j),
+                     std::cmp::Ordering::Greater => self.phi(j, i)
+                 })
+                 .collect())
+            .collect();
+        let flip_rate = if self.matched > 0 {
+            Some(self.flips.iter().map(|&f| f as f32 / self.matched as f32).collect())
+        } else {
+            None
+        };
+        BitDiagnostics {
+            samples: self.samples,
+            matched: self.matched,
+            mean: mean,
+            entropy: entropy,
+            correlation: correlation,
+            flip_rate: flip_rate
+        }
+    }
+}
+
+// per bit statistics of a test set over a dataset. correlation[i][j] is the
+// phi coefficient of bits i and j, from -1 when they always differ to 1 when
+// they always agree, and flip_rate the fraction of matched pairs on which a
+// bit differs
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BitDiagnostics {
+    pub samples: u32,
+    pub matched: u32,
+    pub mean: Vec<f32>,
+    pub entropy: Vec<f32>,
+    pub correlation: Vec<Vec<f32>>,
+    pub flip_rate: Option<Vec<f32>>
+}
+
+impl BitDiagnostics {
+    // a bit is worse the further its mean is from 0.5 and the more often it flips
+    pub fn cost(&self, i:usize) -> f32 {
+        (0.5 - self.mean[i]).abs() + self.flip_rate.as_ref().map_or(0.0, |f| f[i])
+    }
+
+    // the bits, best first
+    pub fn order(&self) -> Vec<usize> {
+        let mut order:Vec<usize> = (0..self.mean.len()).collect();
+        order.sort_by(|&a, &b| self.cost(a).partial_cmp(&self.cost(b)).unwrap());
+        order
+    }
+
+    // the bits, best first, that flip no more often than max_flip_rate and whose
+    // correlation with every better bit kept is at most max_correlation either way
+    pub fn prune(&self, max_correlation:f32, max_flip_rate:f32) -> Vec<usize> {
+        let mut kept = Vec::<usize>::new();
+        for i in self.order() {
+            if self.flip_rate.as_ref().map_or(false, |f| f[i] > max_flip_rate) {
+                continue;
+            }
+            if kept.iter().all(|&k| self.correlation[i][k].abs() <= max_correlation) {
+                kept.push(i);
+            }
+        }
+        kept
+    }
+
+    pub fn save(&self, filename:&str) -> Result<()> {
+        let serialized = serde_json::to_string_pretty(self)?;
+        fs::write(filename, serialized)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bit_statistics() {
+        let mut stats = BitStatistics::new(4);
+        // bit 0 always set, bit 1 alternates, bit 2 copies bit 1, bit 3 inverts it
+        for i in 0..8u128 {
+            let b = i % 2;
+            stats.add(1 | b << 1 | b << 2 | (1 - b) << 3);
+        }
+        stats.add_matched(0b0011, 0b0111);
+        stats.add_matched(0b0011, 0b0011);
+        let d = stats.diagnostics();
+        assert_eq!(d.samples, 8);
+        assert_eq!(d.mean, vec![1.0, 0.5, 0.5, 0.5]);
+        assert_eq!(d.entropy, vec![0.0, 1.0, 1.0, 1.0]);
+        assert_eq!(d.correlation[1][2], 1.0);
+        assert_eq!(d.correlation[3][1], -1.0);
+        // a constant bit agrees with half of an alternating one but carries nothing
+        assert_eq!(d.correlation[0][1], 0.0);
+        assert_eq!(d.correlation[2][0], 0.0);
+        assert_eq!(d.flip_rate, Some(vec![0.0, 0.0, 0.5, 0.0]));
+        assert_eq!(d.order(), vec![1, 3, 0, 2]);
+        // 2 and 3 are redundant with 1
+        assert_eq!(d.prune(0.25, 1.0), vec![1, 0]);
+        assert_eq!(d.prune(0.25, 0.0), vec![1, 0]);
+        assert_eq!(d.prune(0.5, 0.0), vec![1, 0]);
+        assert_eq!(d.prune(1.0, 0.0), vec![1, 3, 0]);
+    }
+}
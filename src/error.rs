@@ -0,0 +1,120 @@
+use std::{error, fmt, io, num, str, string};
+
+// everything the library can fail with
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Image(image::ImageError),
+    Json(serde_json::Error),
+    TomlRead(toml::de::Error),
+    TomlWrite(toml::ser::Error),
+    Zip(zip::result::ZipError),
+    // a file that isn't in the format expected of it
+    Format(String),
+    // an option such as a kernel or resize policy that can't be parsed
+    Parse(String),
+    InvalidConfig(String),
+    InvalidTestSet(String),
+    // an image with no pixels, given as width and height
+    EmptyImage(u32, u32),
+    // a trainer that hasn't accumulated any keypoints to select tests with
+    NoSamples,
+    // trainers or test sets that can't be combined
+    Incompatible(String)
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Image(e) => write!(f, "{}", e),
+            Error::Json(e) => write!(f, "{}", e),
+            Error::TomlRead(e) => write!(f, "{}", e),
+            Error::TomlWrite(e) => write!(f, "{}", e),
+            Error::Zip(e) => write!(f, "{}", e),
+            Error::Format(s) => write!(f, "{}", s),
+            Error::Parse(s) => write!(f, "{}", s),
+            Error::InvalidConfig(s) => write!(f, "invalid config: {}", s),
+            Error::InvalidTestSet(s) => write!(f, "invalid test set: {}", s),
+            Error::EmptyImage(w, h) => write!(f, "image of {}x{} has no pixels", w, h),
+            Error::NoSamples => write!(f, "no keypoints have been accumulated"),
+            Error::Incompatible(s) => write!(f, "{}", s)
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Image(e) => Some(e),
+            Error::Json(e) => Some(e),
+            Error::TomlRead(e) => Some(e),
+            Error::TomlWrite(e) => Some(e),
+            Error::Zip(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e:io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<image::ImageError> for Error {
+    fn from(e:image::ImageError) -> Error {
+        Error::Image(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e:serde_json::Error) -> Error {
+        Error::Json(e)
+    }
+}
+
+impl From<toml::de::Error> for Error {
+    fn from(e:toml::de::Error) -> Error {
+        Error::TomlRead(e)
+    }
+}
+
+impl From<toml::ser::Error> for Error {
+    fn from(e:toml::ser::Error) -> Error {
+        Error::TomlWrite(e)
+    }
+}
+
+impl From<zip::result::ZipError> for Error {
+    fn from(e:zip::result::ZipError) -> Error {
+        Error::Zip(e)
+    }
+}
+
+impl From<num::ParseIntError> for Error {
+    fn from(e:num::ParseIntError) -> Error {
+        Error::Parse(e.to_string())
+    }
+}
+
+impl From<num::ParseFloatError> for Error {
+    fn from(e:num::ParseFloatError) -> Error {
+        Error::Parse(e.to_string())
+    }
+}
+
+impl From<str::Utf8Error> for Error {
+    fn from(e:str::Utf8Error) -> Error {
+        Error::Format(e.to_string())
+    }
+}
+
+impl From<string::FromUtf8Error> for Error {
+    fn from(e:string::FromUtf8Error) -> Error {
+        Error::Format(e.to_string())
+    }
+}
@@ -0,0 +1,408 @@
+use image::GrayImage;
+use imageproc::corners;
+use serde::{de, Serialize, Serializer, Deserialize, Deserializer};
+use memmap2::Mmap;
+use std::fs;
+use std::ops::Range;
+use crate::dataset::ResizePolicy;
+use crate::error::{Error, Result};
+use crate::{Config, Corner, find_multiscale_features};
+
+pub const FEATURE_SET_VERSION:u32 = 1;
+
+// the binary form: a header of HEADER_SIZE bytes, then the descriptors as
+// little endian u128s, then a KEYPOINT_SIZE record for each keypoint
+pub const FEATURE_FILE_MAGIC:&[u8; 4] = b"ORBF";
+pub const FEATURE_FILE_VERSION:u32 = 1;
+const HEADER_SIZE:usize = 64;
+// x, y, angle, score and orientation_confidence as f32, then level and flags as u16
+const KEYPOINT_SIZE:usize = 24;
+const HAS_DESCRIPTOR:u16 = 1;
+
+// a feature that can be saved, located in the full resolution image
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Keypoint {
+    pub x: f32,
+    pub y: f32,
+    pub level: u32,
+    pub angle: f32,
+    pub score: f32,
+    pub orientation_confidence: f32,
+    #[serde(serialize_with = "serialize_descriptor", deserialize_with = "deserialize_descriptor")]
+    pub descriptor: Option<u128>
+}
+
+// descriptors are written as 32 hex digits, as json numbers can't hold 128 bits
+fn serialize_descriptor<S:Serializer>(d:&Option<u128>, serializer:S) -> std::result::Result<S::Ok, S::Error> {
+    d.map(|d| format!("{:032x}", d)).serialize(serializer)
+}
+
+fn deserialize_descriptor<'de, D:Deserializer<'de>>(deserializer:D) -> std::result::Result<Option<u128>, D::Error> {
+    match Option::<String>::deserialize(deserializer)? {
+        Some(hex) => u128::from_str_radix(&hex, 16)
+            .map(Some)
+            .map_err(|_| de::Error::custom(format!("invalid descriptor {}", hex))),
+        None => Ok(None)
+    }
+}
+
+impl Keypoint {
+    pub fn from_corner(c:&Corner) -> Keypoint {
+        let (x, y) = c.position();
+        Keypoint {
+            x: x,
+            y: y,
+            level: c.level,
+            angle: c.angle,
+            score: c.corner.score,
+            orientation_confidence: c.orientation_confidence,
+            descriptor: c.descriptor
+        }
+    }
+
+    // back to a corner at its pyramid level, for matching with find_matches
+    pub fn to_corner(&self) -> Corner {
+        let s = (1 << self.level) as f32;
+        Corner {
+            corner: corners::Corner::new((self.x / s).round() as u32, (self.y / s).round() as u32, self.score),
+            angle: self.angle,
+            orientation_confidence: self.orientation_confidence,
+            descriptor: self.descriptor,
+            level: self.level
+        }
+    }
+}
+
+// FNV-1a of the settings that change which features are found and how they
+// are described, so saved features can be checked against a config
+pub fn config_hash(config:&Config) -> Result<String> {
+    resized_config_hash(config, ResizePolicy::Original)
+}
+
+// as config_hash, for features found in images resized by resize before
+// detection. Using images as they are hashes the same as config_hash.
+pub fn resized_config_hash(config:&Config, resize:ResizePolicy) -> Result<String> {
+    let mut value = serde_json::to_value(config)?;
+    if let Some(map) = value.as_object_mut() {
+        map.retain(|k, _v| !k.starts_with("lsh_"));
+        if resize != ResizePolicy::Original {
+            map.insert("resize".to_string(), serde_json::to_value(resize)?);
+        }
+    }
+    let hash = serde_json::to_string(&value)?.bytes()
+        .fold(0xcbf29ce484222325u64, |h, b| (h ^ b as u64).wrapping_mul(0x100000001b3));
+    Ok(format!("{:016x}", hash))
+}
+
+// the features of one image with what's needed to know if they can be reused
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FeatureSet {
+    pub version: u32,
+    // where the features came from, such as the image's path
+    #[serde(default)]
+    pub image: String,
+    pub width: u32,
+    pub height: u32,
+    pub config_hash: String,
+    pub descriptor_bits: u32,
+    pub features: Vec<Keypoint>
+}
+
+impl FeatureSet {
+    pub fn new(width:u32, height:u32, config:&Config, corners:&[Corner]) -> Result<FeatureSet> {
+        Ok(FeatureSet {
+            version: FEATURE_SET_VERSION,
+            image: String::new(),
+            width: width,
+            height: height,
+            config_hash: config_hash(config)?,
+            descriptor_bits: config.rbrief_test_set.test_set(0.0).set.len() as u32,
+            features: corners.iter().map(Keypoint::from_corner).collect()
+        })
+    }
+
+    pub fn detect(image:&GrayImage, config:&Config) -> Result<FeatureSet> {
+        let corners = find_multiscale_features(image, config)?;
+        FeatureSet::new(image.width(), image.height(), config, &corners)
+    }
+
+    // whether these features are the ones config would find
+    pub fn matches_config(&self, config:&Config) -> bool {
+        config_hash(config).map_or(false, |h| h == self.config_hash)
+    }
+
+    pub fn corners(&self) -> Vec<Corner> {
+        self.features.iter().map(Keypoint::to_corner).collect()
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+
+    pub fn from_json(serialized:&str) -> Result<FeatureSet> {
+        let set:FeatureSet = serde_json::from_str(serialized)?;
+        if set.version > FEATURE_SET_VERSION {
+            return Err(Error::Format(format!("unsupported feature set version {}", set.version)));
+        }
+        Ok(set)
+    }
+
+    pub fn save(&self, filename:&str) -> Result<()> {
+        fs::write(filename, self.to_json()?)?;
+        Ok(())
+    }
+
+    pub fn load(filename:&str) -> Result<FeatureSet> {
+        FeatureSet::from_json(&fs::read_to_string(filename)?)
+    }
+
+    // the binary form read by FeatureFile. Keypoints without a descriptor are
+    // stored with a zero one, flagged as missing
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let hash = u64::from_str_radix(&self.config_hash, 16)
+            .map_err(|_| Error::Format(format!("invalid config hash {}", self.config_hash)))?;
+        let count = self.features.len();
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + count * (16 + KEYPOINT_SIZE));
+        bytes.extend_from_slice(FEATURE_FILE_MAGIC);
+        bytes.extend_from_slice(&FEATURE_FILE_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&self.width.to_le_bytes());
+        bytes.extend_from_slice(&self.height.to_le_bytes());
+        bytes.extend_from_slice(&(count as u64).to_le_bytes());
+        bytes.extend_from_slice(&self.descriptor_bits.to_le_bytes());
+        bytes.extend_from_slice(&hash.to_le_bytes());
+        bytes.resize(HEADER_SIZE, 0);
+        for k in self.features.iter() {
+            bytes.extend_from_slice(&k.descriptor.unwrap_or(0).to_le_bytes());
+        }
+        for k in self.features.iter() {
+            for v in [k.x, k.y, k.angle, k.score, k.orientation_confidence].iter() {
+                bytes.extend_from_slice(&v.to_le_bytes());
+            }
+            bytes.extend_from_slice(&(k.level as u16).to_le_bytes());
+            let flags = if k.descriptor.is_some() { HAS_DESCRIPTOR } else { 0 };
+            bytes.extend_from_slice(&flags.to_le_bytes());
+        }
+        Ok(bytes)
+    }
+
+    pub fn save_binary(&self, filename:&str) -> Result<()> {
+        fs::write(filename, self.to_bytes()?)?;
+        Ok(())
+    }
+}
+
+enum Storage {
+    Mapped(Mmap),
+    // whole u128s so the descriptors are aligned as they are in a mapping
+    Owned(Vec<u128>, usize)
+}
+
+// a binary feature file, read in place. The descriptors can be passed straight
+// to match_descriptors and keypoints are only decoded when asked for.
+pub struct FeatureFile {
+    storage: Storage,
+    count: usize
+}
+
+fn u32_at(bytes:&[u8], offset:usize) -> u32 {
+    let mut word = [0u8; 4];
+    word.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(word)
+}
+
+fn u64_at(bytes:&[u8], offset:usize) -> u64 {
+    let mut word = [0u8; 8];
+    word.copy_from_slice(&bytes[offset..offset + 8]);
+    u64::from_le_bytes(word)
+}
+
+impl FeatureFile {
+    pub fn open(filename:&str) -> Result<FeatureFile> {
+        let file = fs::File::open(filename)?;
+        // the mapping is only valid while nothing else changes the file
+        let map = unsafe { Mmap::map(&file)? };
+        FeatureFile::new(Storage::Mapped(map))
+    }
+
+    pub fn from_bytes(bytes:&[u8]) -> Result<FeatureFile> {
+        let mut words = vec![0u128; (bytes.len() + 15) / 16];
+        for (w, chunk) in words.iter_mut().zip(bytes.chunks(16)) {
+            let mut word = [0u8; 16];
+            word[..chunk.len()].copy_from_slice(chunk);
+            *w = u128::from_ne_bytes(word);
+        }
+        FeatureFile::new(Storage::Owned(words, bytes.len()))
+    }
+
+    fn new(storage:Storage) -> Result<FeatureFile> {
+        let mut file = FeatureFile { storage: storage, count: 0 };
+        let bytes = file.bytes();
+        if bytes.len() < HEADER_SIZE || &bytes[..4] != FEATURE_FILE_MAGIC {
+            return Err(Error::Format("not a binary feature file".to_string()));
+        }
+        let version = u32_at(bytes, 4);
+        if version > FEATURE_FILE_VERSION {
+            return Err(Error::Format(format!("unsupported feature file version {}", version)));
+        }
+        if cfg!(target_endian = "big") {
+            return Err(Error::Format("feature files can only be mapped on little endian machines".to_string()));
+        }
+        let count = u64_at(bytes, 16);
+        let expected = count.checked_mul((16 + KEYPOINT_SIZE) as u64)
+            .and_then(|n| n.checked_add(HEADER_SIZE as u64));
+        if expected != Some(bytes.len() as u64) {
+            return Err(Error::Format(format!("feature file of {} bytes can't hold {} keypoints",
+                                             bytes.len(), count)));
+        }
+        file.count = count as usize;
+        Ok(file)
+    }
+
+    fn bytes(&self) -> &[u8] {
+        match &self.storage {
+            Storage::Mapped(map) => &map[..],
+            Storage::Owned(words, len) =>
+                unsafe { std::slice::from_raw_parts(words.as_ptr() as *const u8, *len) }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn width(&self) -> u32 {
+        u32_at(self.bytes(), 8)
+    }
+
+    pub fn height(&self) -> u32 {
+        u32_at(self.bytes(), 12)
+    }
+
+    pub fn descriptor_bits(&self) -> u32 {
+        u32_at(self.bytes(), 24)
+    }
+
+    pub fn config_hash(&self) -> String {
+        format!("{:016x}", u64_at(self.bytes(), 28))
+    }
+
+    pub fn descriptors(&self) -> &[u128] {
+        let bytes = &self.bytes()[HEADER_SIZE..HEADER_SIZE + self.count * 16];
+        // mappings are page aligned, and owned storage is made of u128s
+        assert_eq!(bytes.as_ptr().align_offset(std::mem::align_of::<u128>()), 0);
+        unsafe { std::slice::from_raw_parts(bytes.as_ptr() as *const u128, self.count) }
+    }
+
+    fn record(&self, i:usize) -> Range<usize> {
+        let start = HEADER_SIZE + self.count * 16 + i * KEYPOINT_SIZE;
+        start..start + KEYPOINT_SIZE
+    }
+
+    pub fn keypoint(&self, i:usize) -> Keypoint {
+        let r = &self.bytes()[self.record(i)];
+        let f = |j:usize| f32::from_bits(u32_at(r, j * 4));
+        let level_flags = u32_at(r, 20);
+        Keypoint {
+            x: f(0),
+            y: f(1),
+            angle: f(2),
+            score: f(3),
+            orientation_confidence: f(4),
+            level: level_flags & 0xffff,
+            descriptor: if (level_flags >> 16) as u16 & HAS_DESCRIPTOR != 0 {
+                Some(self.descriptors()[i])
+            } else {
+                None
+            }
+        }
+    }
+
+    pub fn keypoints(&self) -> impl Iterator<Item = Keypoint> + '_ {
+        (0..self.count).map(move |i| self.keypoint(i))
+    }
+
+    pub fn to_feature_set(&self) -> FeatureSet {
+        FeatureSet {
+            version: FEATURE_SET_VERSION,
+            image: String::new(),
+            width: self.width(),
+            height: self.height(),
+            config_hash: self.config_hash(),
+            descriptor_bits: self.descriptor_bits(),
+            features: self.keypoints().collect()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the features of the test chequerboard found with the default config
+    fn checkerboard_features() -> FeatureSet {
+        FeatureSet::detect(&crate::checkerboard(), &Config::default()).unwrap()
+    }
+
+    #[test]
+    fn test_feature_set() {
+        let config = Config::default();
+        let set = checkerboard_features();
+        assert_eq!((set.width, set.height, set.descriptor_bits), (128, 96, 128));
+        assert!(!set.features.is_empty());
+        let json = set.to_json().unwrap();
+        assert!(json.contains(&format!("\"{:032x}\"", set.features[0].descriptor.unwrap())));
+        assert_eq!(FeatureSet::from_json(&json).unwrap(), set);
+
+        assert!(set.matches_config(&config));
+        let lsh = Config { lsh_max_distance: 30, ..Config::default() };
+        assert!(set.matches_config(&lsh));
+        let upright = Config { upright: true, ..Config::default() };
+        assert!(!set.matches_config(&upright));
+
+        // corners rebuilt from the keypoints match as the originals do
+        let corners = find_multiscale_features(&crate::checkerboard(), &config).unwrap();
+        let rebuilt = set.corners();
+        assert!(rebuilt.iter().zip(corners.iter()).all(|(a, b)|
+            (a.corner.x, a.corner.y, a.level, a.descriptor) == (b.corner.x, b.corner.y, b.level, b.descriptor)));
+
+        assert!(FeatureSet::from_json(&json.replace("\"version\":1", "\"version\":9")).is_err());
+        let d = format!("{:032x}", set.features[0].descriptor.unwrap());
+        assert!(FeatureSet::from_json(&json.replacen(&d, "xyz", 1)).is_err());
+    }
+
+    #[test]
+    fn test_feature_file() {
+        let config = Config::default();
+        let mut set = checkerboard_features();
+        set.features[1].descriptor = None;
+        let filename = std::env::temp_dir().join("image_processing_test_features.bin");
+        let filename = filename.to_str().unwrap();
+        set.save_binary(filename).unwrap();
+        let file = FeatureFile::open(filename).unwrap();
+        assert_eq!(file.len(), set.features.len());
+        assert_eq!(file.config_hash(), set.config_hash);
+        assert_eq!(file.to_feature_set(), set);
+        assert_eq!(file.descriptors()[0], set.features[0].descriptor.unwrap());
+        fs::remove_file(filename).unwrap();
+
+        // matching the descriptors against themselves finds each one
+        let matches = crate::match_descriptors(file.descriptors(), file.descriptors(), &config).unwrap();
+        assert!(matches.iter().enumerate().all(|(i, m)| m.map_or(false, |j|
+            file.descriptors()[j] == file.descriptors()[i])));
+
+        let bytes = set.to_bytes().unwrap();
+        assert_eq!(FeatureFile::from_bytes(&bytes).unwrap().keypoint(2), set.features[2]);
+        assert!(FeatureFile::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(FeatureFile::from_bytes(b"ORBF").is_err());
+        // a count whose size overflows is rejected rather than wrapping
+        let mut huge = bytes.clone();
+        huge[16..24].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(FeatureFile::from_bytes(&huge).is_err());
+        let empty = FeatureSet { features: Vec::new(), ..set };
+        assert!(FeatureFile::from_bytes(&empty.to_bytes().unwrap()).unwrap().is_empty());
+    }
+}
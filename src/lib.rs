@@ -1,9 +1,40 @@
 extern crate nalgebra as na;
 use image::{GrayImage, Luma, imageops};
-use imageproc::{corners, gradients};
+use imageproc::{corners, gradients, noise};
+use imageproc::geometric_transformations::{warp, Interpolation, Projection};
+use rand::Rng;
+use serde::{Serialize, Deserialize};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::path::Path;
 use num;
+
+// iterate over the items in parallel with the parallel feature, otherwise in
+// turn. Either way collecting keeps the items in order.
+#[cfg(feature = "parallel")]
+macro_rules! maybe_par_iter {
+    ($items:expr) => { $items.into_par_iter() }
+}
+#[cfg(not(feature = "parallel"))]
+macro_rules! maybe_par_iter {
+    ($items:expr) => { $items.into_iter() }
+}
+
+pub mod batch;
+pub mod colmap;
+pub mod dataset;
+pub mod diagnostics;
+pub mod error;
+pub mod features;
+pub mod npy;
+pub mod opencv;
 pub mod rbrief;
+pub mod registration;
+pub mod visualise;
 use hamming_lsh;
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+pub use error::{Error, Result};
 
 pub struct Pyramid {
     pub images: Vec::<GrayImage>
@@ -15,13 +46,69 @@ fn get_safe_from_image(image: &GrayImage, x:i32, y:i32) -> Luma<u8> {
     *image.get_pixel(sx as u32, sy as u32)
 }
 
+// mirror i about the ends of 0..n without repeating the edge
+fn reflect_index(i:i32, n:i32) -> i32 {
+    if n == 1 {
+        return 0;
+    }
+    let period = 2 * (n - 1);
+    let i = i.rem_euclid(period);
+    if i < n { i } else { period - i }
+}
+
 fn get_safe_from_vec(v: &Vec<u8>, i: i32) -> u8 {
     let si = num::clamp(i, 0, v.len() as i32 - 1);
     v[si as usize]
 }
 
+// row y of the binomial filtered half size level of w x h below src
+fn downsample_row(src:&GrayImage, w:u32, h:u32, y:u32) -> Vec<u8> {
+    let kernel = [(-2, 1), (-1, 4), (0, 6), (1, 4), (2, 1)];
+    let divisor = 16;
+    let sw = w * 2;
+    let mut row = Vec::<u8>::new();
+    let y2 = (y as i32) * 2;
+    if y2 - 2 < 0 || y2 + 2 > (h as i32) - 1 {
+        // top / bottom where kernel overlaps image edge
+        for sx in 0..sw {
+            let sum = kernel.iter()
+                .fold(0, |sum, (a, w)|
+                    sum + (get_safe_from_image(src, sx as i32, y2 + a)[0] as u32) * w);
+            row.push((sum / divisor) as u8);
+        }
+    } else {
+        for sx in 0..sw {
+            let sum = kernel.iter()
+                .fold(0, |sum, (a, w)|
+                    sum + (src.get_pixel(sx as u32, (y2 + a) as u32)[0] as u32) * w);
+            row.push((sum / divisor) as u8);
+        }
+    }
+
+    let mut dst = vec![0u8; w as usize];
+    for x in 0..w {
+        // left / right where kernel overlaps vec ends
+        let sum = if x == 0 || x == w - 1 {
+            kernel.iter()
+                .fold(0, |sum, (a, w)|
+                    sum + (get_safe_from_vec(&row, (x as i32) *2 + a) as u32) * w)
+        } else {
+            kernel.iter()
+                .fold(0, |sum, (a, w)|
+                    sum + (row[((x as i32) * 2 + a) as usize] as u32) * w)
+        };
+        dst[x as usize] = (sum / divisor) as u8;
+    }
+    dst
+}
+
 impl Pyramid {
-    pub fn new(src_image:&GrayImage, levels:u32) -> Pyramid {
+    // levels stop once they would be less than a pixel wide
+    pub fn new(src_image:&GrayImage, levels:u32) -> Result<Pyramid> {
+        let (w, h) = src_image.dimensions();
+        if w == 0 || h == 0 {
+            return Err(Error::EmptyImage(w, h));
+        }
         let mut images = Vec::<GrayImage>::new();
         images.push(src_image.clone());
         let (mut w, mut h) = src_image.dimensions();
@@ -32,65 +119,32 @@ impl Pyramid {
             if w == 0 || h == 0 {
                 break;
             }
-            // binomial
-            let kernel = [(-2, 1), (-1, 4), (0, 6), (1, 4), (2, 1)];
-            let divisor = 16;
-            let mut dst = GrayImage::new(w, h);
-            for y in 0..h {
-                let sw = w * 2;
-                let mut row = Vec::<u8>::new();
-                let y2 = (y as i32) * 2;
-                if y2 - 2 < 0 || y2 + 2 > (h as i32) - 1 {
-                    // top / bottom where kernel overlaps image edge
-                    for sx in 0..sw {
-                        let sum = kernel.iter()
-                            .fold(0, |sum, (a, w)|
-                                sum + (get_safe_from_image(src, sx as i32, y2 + a)[0] as u32) * w);
-                        row.push((sum / divisor) as u8);
-                    }
-                } else {
-                    for sx in 0..sw {
-                        let sum = kernel.iter()
-                            .fold(0, |sum, (a, w)|
-                                sum + (src.get_pixel(sx as u32, (y2 + a) as u32)[0] as u32) * w);
-                        row.push((sum / divisor) as u8);
-                    }
-                }
-
-                // left / right where kernel overlaps vec ends
-                let x = 0;
-                let sum = kernel.iter()
-                    .fold(0, |sum, (a, w)|
-                        sum + (get_safe_from_vec(&row, (x as i32) *2 + a) as u32) * w);
-                dst.put_pixel(x, y, Luma([(sum / divisor) as u8]));
-                let x = w - 1;
-                let sum = kernel.iter()
-                    .fold(0, |sum, (a, w)|
-                        sum + (get_safe_from_vec(&row, (x as i32) *2 + a) as u32) * w);
-                dst.put_pixel(x, y, Luma([(sum / divisor) as u8]));
-
-                for x in 1..(w - 1) {
-                    let sum = kernel.iter()
-                        .fold(0, |sum, (a, w)|
-                            sum + (row[((x as i32) * 2 + a) as usize] as u32) * w);
-                    dst.put_pixel(x, y, Luma([(sum / divisor) as u8]));
-                }
-            }
+            // each row only reads src, so rows can be filtered in parallel
+            let rows:Vec<Vec<u8>> = maybe_par_iter!(0..h)
+                .map(|y| downsample_row(src, w, h, y))
+                .collect();
+            let dst = GrayImage::from_raw(w, h, rows.concat()).unwrap();
             images.push(dst);
             src = &images[images.len() - 1];
         }
 
-        Pyramid {
+        Ok(Pyramid {
             images: images
-        }
+        })
     }
 }
 
+// the half width of each row of a circular patch, for rows -r to r
+fn circular_extents(r:f32) -> Vec<i32> {
+    (-r as i32..(r + 1.0) as i32)
+        .map(|y| (r * r - (y * y) as f32).sqrt() as i32)
+        .collect()
+}
+
 fn circular_window(r:f32) ->Vec<(i32, i32)> {
     let mut offsets = Vec::<(i32, i32)>::new();
-    for y in (-r as i32)..((r + 1.0) as i32) {
-        let x_max = (r * r - (y * y) as f32).sqrt();
-        for x in (-x_max as i32)..((x_max + 1.0) as i32) {
+    for (y, x_max) in ((-r as i32)..).zip(circular_extents(r).iter()) {
+        for x in -x_max..(x_max + 1) {
             offsets.push((x, y));
         }
     }
@@ -124,6 +178,11 @@ fn harris_score(src:&GrayImage, x:u32, y:u32, r:u32) -> f32 {
 }
 
 pub fn find_features(src:&GrayImage, threshold:u8) -> Vec<corners::Corner> {
+    // FAST compares each pixel with a circle of radius 3 around it
+    let (w, h) = src.dimensions();
+    if w < 7 || h < 7 {
+        return Vec::new();
+    }
     let corners = corners::corners_fast9(src, threshold);
 
     corners.iter()
@@ -136,29 +195,90 @@ pub fn find_features(src:&GrayImage, threshold:u8) -> Vec<corners::Corner> {
     .collect()
 }
 
-pub fn orientation(image:&GrayImage, x:u32, y:u32, r:u32) -> f32 {
-    if x < r || y < r || x > image.width() - r || y > image.height() -r {
-        return 0.0;
+// a circular patch used to find the intensity centroid of a corner
+pub struct OrientationPatch {
+    radius: u32,
+    extents: Vec<i32>
+}
+
+impl OrientationPatch {
+    pub fn new(r:u32) -> OrientationPatch {
+        OrientationPatch {
+            radius: r,
+            extents: circular_extents(r as f32 + 0.5)
+        }
     }
-    // mpq = sum((x^p)*(y^q)*I(x,y))
-    // theta = atan2(m01, m10)
-    let mut m01:f32 = 0.0;
-    let mut m10:f32 = 0.0;
-    let window = circular_window(r as f32 + 0.5);
-    let xi = x as i32;
-    let yi = y as i32;
-    for (i, j) in window.iter() {
-        let Luma([p]) = image.get_pixel((xi + i) as u32, (yi + j) as u32);
-        m01 += (*j as f32) * (*p as f32);
-        m10 += (*i as f32) * (*p as f32);
+
+    pub fn radius(&self) -> u32 {
+        self.radius
     }
-    m01.atan2(m10)
+
+    // returns the angle of the intensity centroid and its distance from the
+    // patch centre in pixels, which is near zero where the angle is unreliable
+    pub fn centroid(&self, image:&GrayImage, x:u32, y:u32) -> (f32, f32) {
+        let r = self.radius;
+        if x < r || y < r || x + r >= image.width() || y + r >= image.height() {
+            return (0.0, 0.0);
+        }
+        // mpq = sum((x^p)*(y^q)*I(x,y))
+        // theta = atan2(m01, m10)
+        let mut m00:f32 = 0.0;
+        let mut m01:f32 = 0.0;
+        let mut m10:f32 = 0.0;
+        let xi = x as i32;
+        let yi = y as i32;
+        for (j, x_max) in (-(r as i32)..).zip(self.extents.iter()) {
+            for i in -x_max..(x_max + 1) {
+                let Luma([p]) = image.get_pixel((xi + i) as u32, (yi + j) as u32);
+                let p = *p as f32;
+                m00 += p;
+                m01 += (j as f32) * p;
+                m10 += (i as f32) * p;
+            }
+        }
+        let magnitude = if m00 > 0.0 { (m01 * m01 + m10 * m10).sqrt() / m00 } else { 0.0 };
+        (m01.atan2(m10), magnitude)
+    }
+}
+
+pub fn orientation(image:&GrayImage, x:u32, y:u32, r:u32) -> f32 {
+    OrientationPatch::new(r).centroid(image, x, y).0
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum BorderPolicy {
+    // discard corners whose patch crosses the border before selecting the best
+    Drop,
+    // describe every corner from levels padded by mirroring about the edges
+    Reflect,
+    // describe every corner from levels padded by repeating the edge pixels
+    Replicate
+}
+
+pub fn pad_image(image:&GrayImage, pad:u32, policy:BorderPolicy) -> GrayImage {
+    let (w, h) = image.dimensions();
+    let p = pad as i32;
+    GrayImage::from_fn(w + 2 * pad, h + 2 * pad, |x, y| {
+        let (x, y) = (x as i32 - p, y as i32 - p);
+        match policy {
+            BorderPolicy::Reflect => *image.get_pixel(
+                reflect_index(x, w as i32) as u32, reflect_index(y, h as i32) as u32),
+            _ => get_safe_from_image(image, x, y)
+        }
+    })
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
 pub struct Config {
     pub num_features: usize,
     pub fast_threshold: u8,
     pub num_pyramid_levels: u32,
+    pub orientation_radius: u32,
+    // skip orientation estimation and describe every corner with the
+    // unrotated test set
+    pub upright: bool,
+    pub border_policy: BorderPolicy,
     pub rbrief_test_set: rbrief::RBrief,
     pub lsh_k_l: (u32, u32),
     pub lsh_max_distance: u32
@@ -170,38 +290,172 @@ impl Default for Config {
             num_features: 500,
             fast_threshold: 32,
             num_pyramid_levels: 4,
+            orientation_radius: rbrief::HWIDTH,
+            upright: false,
+            border_policy: BorderPolicy::Drop,
             rbrief_test_set: rbrief::RBrief::from_test_set(
-                rbrief::TestSet::load("res/trained_test_set.json").unwrap()),
+                rbrief::TestSet::trained()),
             lsh_k_l: (4, 10),
             lsh_max_distance: 15,
         }
     }
 }
 
+pub const PRESETS:[&str; 4] = ["default", "fast", "accurate", "slam"];
+
+impl Config {
+    // a named starting point, see PRESETS
+    pub fn preset(name:&str) -> Result<Config> {
+        let mut config = Config::default();
+        match name {
+            "default" => (),
+            // fewer, stronger corners over fewer levels
+            "fast" => {
+                config.num_features = 250;
+                config.fast_threshold = 48;
+                config.num_pyramid_levels = 3;
+                config.lsh_k_l = (8, 6);
+            },
+            // more corners, described right up to the border
+            "accurate" => {
+                config.num_features = 1500;
+                config.fast_threshold = 20;
+                config.num_pyramid_levels = 6;
+                config.border_policy = BorderPolicy::Reflect;
+                config.lsh_k_l = (4, 16);
+                config.lsh_max_distance = 20;
+            },
+            // a steady number of well spread features from frame to frame
+            "slam" => {
+                config.num_features = 1000;
+                config.fast_threshold = 24;
+                config.num_pyramid_levels = 5;
+                config.border_policy = BorderPolicy::Replicate;
+                config.lsh_max_distance = 25;
+            },
+            _ => return Err(Error::InvalidConfig(format!("unknown preset {}, expected one of {:?}", name, PRESETS)))
+        }
+        Ok(config)
+    }
+
+    // the length of the descriptors made with the configured test set
+    fn descriptor_bits(&self) -> u32 {
+        self.rbrief_test_set.test_set(0.0).set.len() as u32
+    }
+
+    // reject combinations that can't find or match any features
+    pub fn validate(&self) -> Result<()> {
+        let bits = self.descriptor_bits();
+        let invalid = |s:String| Err(Error::InvalidConfig(s));
+        if self.num_features == 0 {
+            return invalid("num_features must be at least 1".to_string());
+        }
+        if self.fast_threshold == 0 {
+            return invalid("fast_threshold must be at least 1".to_string());
+        }
+        if self.num_pyramid_levels == 0 || self.num_pyramid_levels > 16 {
+            return invalid(format!("num_pyramid_levels must be 1 to 16, not {}", self.num_pyramid_levels));
+        }
+        if self.orientation_radius == 0 {
+            return invalid("orientation_radius must be at least 1".to_string());
+        }
+        self.validate_lsh(bits)?;
+        if self.lsh_max_distance > bits {
+            return invalid(format!("lsh_max_distance {} is beyond the {} bit descriptor",
+                                   self.lsh_max_distance, bits));
+        }
+        self.rbrief_test_set.test_set(0.0).validate()
+    }
+
+    fn validate_lsh(&self, bits:u32) -> Result<()> {
+        if self.lsh_k_l.0 > bits {
+            return Err(Error::InvalidConfig(format!("lsh_k_l samples {} bits of a {} bit descriptor",
+                                                    self.lsh_k_l.0, bits)));
+        }
+        if self.lsh_k_l.1 == 0 {
+            return Err(Error::InvalidConfig("lsh_k_l needs at least one table".to_string()));
+        }
+        Ok(())
+    }
+
+    pub fn from_toml(serialized:&str) -> Result<Config> {
+        let config:Config = toml::from_str(serialized)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_json(serialized:&str) -> Result<Config> {
+        let config:Config = serde_json::from_str(serialized)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn to_toml(&self) -> Result<String> {
+        // toml can't write enums holding values, which json writes as maps,
+        // and going through a Value puts tables after plain values as toml requires
+        let value = toml::Value::try_from(serde_json::to_value(self)?)?;
+        Ok(toml::to_string(&value)?)
+    }
+
+    // load a .toml or .json file, missing fields taking their default values
+    // test set files named in the config are relative to its folder
+    pub fn load(filename:&str) -> Result<Config> {
+        let serialized = std::fs::read_to_string(filename)?;
+        let dir = Path::new(filename).parent().unwrap_or(Path::new(""));
+        rbrief::with_config_dir(dir, || if filename.to_lowercase().ends_with(".toml") {
+            Config::from_toml(&serialized)
+        } else {
+            Config::from_json(&serialized)
+        })
+    }
+
+    pub fn save(&self, filename:&str) -> Result<()> {
+        let serialized = if filename.to_lowercase().ends_with(".toml") {
+            self.to_toml()?
+        } else {
+            serde_json::to_string_pretty(self)?
+        };
+        std::fs::write(filename, serialized)?;
+        Ok(())
+    }
+}
+
 pub struct Corner {
     pub corner: corners::Corner,
     pub angle: f32,
+    pub orientation_confidence: f32,
     pub descriptor: Option<u128>,
     pub level: u32
 }
 
+impl Corner {
+    // the location in the full resolution image
+    pub fn position(&self) -> (f32, f32) {
+        let s = (1 << self.level) as f32;
+        (self.corner.x as f32 * s, self.corner.y as f32 * s)
+    }
+}
+
 struct LevelCorner {
     corner: corners::Corner,
     level: u32
 }
 
 fn find_features_in_pyramid(pyramid:&Pyramid, config:&Config) -> Vec<LevelCorner> {
-    let mut level_corners = Vec::<LevelCorner>::new();
-    
-    for (i, image) in pyramid.images.iter().enumerate() {
-        for f in find_features(image, config.fast_threshold).iter() {
-            level_corners.push(
-                LevelCorner {
-                    level: i as u32,
-                    corner: *f
-                })
-        }
-    }
+    // levels are detected independently and joined in level order
+    let mut level_corners:Vec<LevelCorner> = maybe_par_iter!(&pyramid.images).enumerate()
+        .map(|(i, image)| find_features(image, config.fast_threshold).into_iter()
+            .filter(|f| config.border_policy != BorderPolicy::Drop
+                || config.rbrief_test_set.can_describe(image, f.x, f.y))
+            .map(|f| LevelCorner {
+                level: i as u32,
+                corner: f
+            })
+            .collect::<Vec<_>>())
+        .collect::<Vec<_>>()
+        .into_iter()
+        .flatten()
+        .collect();
 
     // sort by score and pick the top num_features
     level_corners.sort_by(|a, b| a.corner.score.partial_cmp(&b.corner.score).unwrap());
@@ -211,33 +465,87 @@ fn find_features_in_pyramid(pyramid:&Pyramid, config:&Config) -> Vec<LevelCorner
     level_corners
 }
 
+// the pyramid levels that corners are described from, padded if the border
+// policy describes corners near the edge and prepared once for the kernel
+struct DescriptionLevels<'a> {
+    levels: Vec<rbrief::KernelImage<'a>>,
+    pad: u32
+}
+
+impl<'a> DescriptionLevels<'a> {
+    fn new(pyramid:&'a Pyramid, config:&Config, kernel:rbrief::Kernel) -> DescriptionLevels<'a> {
+        let pad = match config.border_policy {
+            BorderPolicy::Drop => 0,
+            _ => std::cmp::max(kernel.radius(), config.orientation_radius + 1)
+        };
+        let levels = maybe_par_iter!(&pyramid.images)
+            .map(|image| rbrief::KernelImage::new(if pad > 0 {
+                Cow::Owned(pad_image(image, pad, config.border_policy))
+            } else {
+                Cow::Borrowed(image)
+            }, kernel))
+            .collect();
+        DescriptionLevels {
+            levels: levels,
+            pad: pad
+        }
+    }
+
+    fn level(&self, level:usize) -> &rbrief::KernelImage<'a> {
+        &self.levels[level]
+    }
+
+    // the level and the corner location within it
+    fn get(&self, c:&LevelCorner) -> (&rbrief::KernelImage<'a>, u32, u32) {
+        (self.level(c.level as usize), c.corner.x + self.pad, c.corner.y + self.pad)
+    }
+
+    // the keypoint (x, y, angle) within the level for x, y of the unpadded level
+    fn keypoint(&self, level:usize, x:u32, y:u32, patch:&OrientationPatch, upright:bool) -> (u32, u32, f32) {
+        let (x, y) = (x + self.pad, y + self.pad);
+        let angle = if upright { 0.0 } else { patch.centroid(self.level(level).image(), x, y).0 };
+        (x, y, angle)
+    }
+}
+
 fn find_and_describe_features_in_pyramid(pyramid:&Pyramid, config:&Config) -> Vec<Corner> {
     let level_corners = find_features_in_pyramid(pyramid, config);
     let tests = &config.rbrief_test_set;
 
-    fn describe_corner(pyramid:&Pyramid, tests:&rbrief::RBrief, c:&LevelCorner) -> Corner {
-        let image = &pyramid.images[c.level as usize];
-        let angle = orientation(image, c.corner.x, c.corner.y, 3);
-        let descriptor = tests.describe(image, c.corner.x, c.corner.y, angle);
+    let patch = OrientationPatch::new(config.orientation_radius);
+    let levels = DescriptionLevels::new(pyramid, config, tests.kernel());
+
+    fn describe_corner(levels:&DescriptionLevels, tests:&rbrief::RBrief, patch:&OrientationPatch,
+                       upright:bool, c:&LevelCorner) -> Corner {
+        let (level, x, y) = levels.get(c);
+        let (angle, confidence, descriptor) = if upright {
+            (0.0, 0.0, tests.describe_upright_prepared(level, x, y))
+        } else {
+            let (angle, confidence) = patch.centroid(level.image(), x, y);
+            (angle, confidence, tests.describe_prepared(level, x, y, angle))
+        };
         Corner {
             corner: c.corner,
             angle: angle,
+            orientation_confidence: confidence,
             descriptor: descriptor,
             level: c.level
         }
     }
 
-    level_corners.iter()
-        .map(|c| describe_corner(pyramid, tests, c))
+    maybe_par_iter!(&level_corners)
+        .map(|c| describe_corner(&levels, tests, &patch, config.upright, c))
         .collect()
 }
 
-pub fn find_multiscale_features(image:&GrayImage, config:&Config) -> Vec<Corner> {
-    let pyramid = Pyramid::new(&image, config.num_pyramid_levels);
-    find_and_describe_features_in_pyramid(&pyramid, config)
+// images too small to hold a corner have no features, empty images are an error
+pub fn find_multiscale_features(image:&GrayImage, config:&Config) -> Result<Vec<Corner>> {
+    let pyramid = Pyramid::new(&image, config.num_pyramid_levels)?;
+    Ok(find_and_describe_features_in_pyramid(&pyramid, config))
 }
 
-pub fn find_matches<'a>(a:&'a Vec<Corner>, b:&Vec<Corner>, config:&Config) -> Vec<Option<&'a Corner>> {
+pub fn find_matches<'a>(a:&'a Vec<Corner>, b:&Vec<Corner>, config:&Config) -> Result<Vec<Option<&'a Corner>>> {
+    config.validate_lsh(config.descriptor_bits())?;
     let mut lsh = hamming_lsh::HammingLSH::new(
         config.lsh_k_l.0, config.lsh_k_l.1);
     
@@ -247,34 +555,373 @@ pub fn find_matches<'a>(a:&'a Vec<Corner>, b:&Vec<Corner>, config:&Config) -> Ve
         }
     }
 
-    b.iter()
+    Ok(b.iter()
         .map(|c| if let Some(d) = c.descriptor { 
             lsh.get(d, Some(config.lsh_max_distance)) } else { None })
         .map(|m| if let Some(m) = m { Some(*m.1) } else { None })
+        .collect())
+}
+
+// as find_matches, for descriptors held in arrays such as a features::FeatureFile,
+// giving the index in a of the match for each descriptor of b
+pub fn match_descriptors(a:&[u128], b:&[u128], config:&Config) -> Result<Vec<Option<usize>>> {
+    config.validate_lsh(config.descriptor_bits())?;
+    let indices:Vec<usize> = (0..a.len()).collect();
+    let mut lsh = hamming_lsh::HammingLSH::new(
+        config.lsh_k_l.0, config.lsh_k_l.1);
+    for (d, i) in a.iter().zip(indices.iter()) {
+        lsh.insert(*d, i);
+    }
+    Ok(b.iter()
+        .map(|&d| lsh.get(d, Some(config.lsh_max_distance)).map(|m| **m.1))
+        .collect())
+}
+
+// the index in a of each match returned by find_matches
+pub fn match_indices(a:&[Corner], matches:&[Option<&Corner>]) -> Vec<Option<usize>> {
+    let range = a.as_ptr_range();
+    matches.iter()
+        .map(|m| m.and_then(|m| {
+            let p = m as *const Corner;
+            // only a pointer into a has an offset from its start
+            if range.contains(&p) {
+                Some(unsafe { p.offset_from(range.start) } as usize)
+            } else {
+                None
+            }
+        }))
+        .collect()
+}
+
+// the positions of each corner of b and its match in a, with the index in b
+pub fn matched_points(b:&[Corner], matches:&[Option<&Corner>]) -> Vec<(usize, registration::PointPair)> {
+    b.iter().zip(matches.iter()).enumerate()
+        .filter_map(|(i, (c, m))| m.map(|m| (i, (c.position(), m.position()))))
         .collect()
 }
 
-pub fn add_image_to_trainer(trainer:&mut rbrief::Trainer, image:&GrayImage, config:&Config) {
-    let pyramid = Pyramid::new(&image, config.num_pyramid_levels);
+pub fn add_image_to_trainer(trainer:&mut rbrief::Trainer, image:&GrayImage, config:&Config) -> Result<()> {
+    let pyramid = Pyramid::new(&image, config.num_pyramid_levels)?;
+    let level_corners = find_features_in_pyramid(&pyramid, config);
+    let patch = OrientationPatch::new(config.orientation_radius);
+    let levels = DescriptionLevels::new(&pyramid, config, trainer.kernel());
+    let mut keypoints = vec![Vec::<(u32, u32, f32)>::new(); pyramid.images.len()];
+    for c in level_corners {
+        let level = c.level as usize;
+        keypoints[level].push(levels.keypoint(level, c.corner.x, c.corner.y, &patch, config.upright));
+    }
+    for (level, k) in keypoints.iter().enumerate() {
+        trainer.accumulate_prepared(levels.level(level), k);
+    }
+    Ok(())
+}
+
+// the range of random transformations used to make matching pairs of
+// patches for supervised training
+#[derive(Clone, Debug)]
+pub struct WarpRange {
+    pub max_rotation: f32,
+    pub min_scale: f32,
+    pub max_scale: f32,
+    // the largest change in the projective w term across the image
+    pub max_perspective: f32,
+    pub noise_stddev: f64
+}
+
+impl Default for WarpRange {
+    fn default() -> WarpRange {
+        WarpRange {
+            max_rotation: std::f32::consts::PI,
+            min_scale: 0.75,
+            max_scale: 1.33,
+            max_perspective: 0.1,
+            noise_stddev: 4.0
+        }
+    }
+}
+
+fn uniform<R:Rng>(rng:&mut R, low:f32, high:f32) -> f32 {
+    if high > low { rng.gen_range(low, high) } else { low }
+}
+
+fn project(m:&na::Matrix3<f32>, x:f32, y:f32) -> (f32, f32) {
+    let p = m * na::Vector3::new(x, y, 1.0);
+    (p.x / p.z, p.y / p.z)
+}
+
+impl WarpRange {
+    // a random homography about the centre of a w x h image and its scale
+    pub fn sample<R:Rng>(&self, w:u32, h:u32, rng:&mut R) -> (na::Matrix3<f32>, f32) {
+        let theta = uniform(rng, -self.max_rotation, self.max_rotation);
+        let scale = uniform(rng, self.min_scale.ln(), self.max_scale.ln()).exp();
+        let px = uniform(rng, -self.max_perspective, self.max_perspective) / w as f32;
+        let py = uniform(rng, -self.max_perspective, self.max_perspective) / h as f32;
+        let (cx, cy) = (w as f32 / 2.0, h as f32 / 2.0);
+        let (c, s) = (scale * theta.cos(), scale * theta.sin());
+        let to_centre = na::Matrix3::new(1.0, 0.0, -cx, 0.0, 1.0, -cy, 0.0, 0.0, 1.0);
+        let similarity = na::Matrix3::new(c, -s, 0.0, s, c, 0.0, 0.0, 0.0, 1.0);
+        let perspective = na::Matrix3::new(1.0, 0.0, 0.0, 0.0, 1.0, 0.0, px, py, 1.0);
+        let from_centre = na::Matrix3::new(1.0, 0.0, cx, 0.0, 1.0, cy, 0.0, 0.0, 1.0);
+        (from_centre * perspective * similarity * to_centre, scale)
+    }
+}
+
+// the locations (x, y) of corners and where the warp takes them, grouped by
+// the level of the pyramid of the image and of the warped image they are found at
+type WarpedPairs = BTreeMap<(usize, usize), Vec<((u32, u32), (u32, u32))>>;
+
+// warp image by a random transform from range, find features in the original
+// and pair each with where the warp takes it, or None if the transform sampled
+// can't be inverted
+fn warped_pairs<R:Rng>(image:&GrayImage, config:&Config, range:&WarpRange, rng:&mut R)
+    -> Result<Option<(Pyramid, Pyramid, WarpedPairs)>> {
+    let (w, h) = image.dimensions();
+    if w == 0 || h == 0 {
+        return Err(Error::EmptyImage(w, h));
+    }
+    let (m, scale) = range.sample(w, h, rng);
+    let projection = match Projection::from_matrix([
+            m[(0, 0)], m[(0, 1)], m[(0, 2)],
+            m[(1, 0)], m[(1, 1)], m[(1, 2)],
+            m[(2, 0)], m[(2, 1)], m[(2, 2)]]) {
+        Some(projection) => projection,
+        None => return Ok(None)
+    };
+    let warped = warp(image, &projection, Interpolation::Bilinear, Luma([0]));
+    let warped = if range.noise_stddev > 0.0 {
+        noise::gaussian_noise(&warped, 0.0, range.noise_stddev, rng.gen())
+    } else {
+        warped
+    };
+
+    let pyramid = Pyramid::new(image, config.num_pyramid_levels)?;
+    let warped_pyramid = Pyramid::new(&warped, config.num_pyramid_levels)?;
     let level_corners = find_features_in_pyramid(&pyramid, config);
+
+    // group the pairs by the levels they are described at in each image
+    let mut groups = WarpedPairs::new();
+    let num_warped_levels = warped_pyramid.images.len() as i32;
     for c in level_corners {
-        let im = &pyramid.images[c.level as usize];
-        let angle = orientation(im, c.corner.x, c.corner.y, 3);
-        trainer.accumulate(im, c.corner.x, c.corner.y, angle);
+        let level = c.level as usize;
+        let s = (1 << c.level) as f32;
+        let (x, y) = project(&m, c.corner.x as f32 * s, c.corner.y as f32 * s);
+        let warped_level = num::clamp(
+            (c.level as f32 + scale.log2()).round() as i32, 0, num_warped_levels - 1) as usize;
+        let ws = (1 << warped_level) as f32;
+        let (x, y) = ((x / ws).round(), (y / ws).round());
+        let warped_image = &warped_pyramid.images[warped_level];
+        if x < 0.0 || y < 0.0 || x >= warped_image.width() as f32 || y >= warped_image.height() as f32 {
+            continue;
+        }
+        groups.entry((level, warped_level)).or_insert_with(Vec::new)
+            .push(((c.corner.x, c.corner.y), (x as u32, y as u32)));
+    }
+    Ok(Some((pyramid, warped_pyramid, groups)))
+}
+
+// the keypoints (x, y, angle) of pairs found at level of the image and
+// warped_level of the warped image, as they are described from each
+fn oriented_pairs(levels:&DescriptionLevels, warped_levels:&DescriptionLevels, (level, warped_level):(usize, usize),
+                  pairs:&[((u32, u32), (u32, u32))], config:&Config) -> Vec<((u32, u32, f32), (u32, u32, f32))> {
+    let patch = OrientationPatch::new(config.orientation_radius);
+    pairs.iter()
+        .map(|&((x, y), (wx, wy))| (levels.keypoint(level, x, y, &patch, config.upright),
+                                    warped_levels.keypoint(warped_level, wx, wy, &patch, config.upright)))
+        .collect()
+}
+
+pub fn add_image_to_supervised_trainer<R:Rng>(trainer:&mut rbrief::SupervisedTrainer,
+                                              image:&GrayImage, config:&Config,
+                                              range:&WarpRange, rng:&mut R) -> Result<()> {
+    if let Some((pyramid, warped_pyramid, groups)) = warped_pairs(image, config, range, rng)? {
+        let levels = DescriptionLevels::new(&pyramid, config, trainer.kernel());
+        let warped_levels = DescriptionLevels::new(&warped_pyramid, config, trainer.kernel());
+        for (&(level, warped_level), pairs) in groups.iter() {
+            let keypoints = oriented_pairs(&levels, &warped_levels, (level, warped_level), pairs, config);
+            trainer.accumulate_prepared_pairs(levels.level(level), warped_levels.level(warped_level), &keypoints);
+        }
+    }
+    Ok(())
+}
+
+// gather per bit statistics of the configured test set from the descriptors of image
+pub fn add_image_to_diagnostics(stats:&mut diagnostics::BitStatistics, image:&GrayImage,
+                                config:&Config) -> Result<()> {
+    for c in find_multiscale_features(image, config)? {
+        if let Some(d) = c.descriptor {
+            stats.add(d);
+        }
+    }
+    Ok(())
+}
+
+// as add_image_to_diagnostics, but also measure how often each bit flips between
+// a keypoint and the same point in a randomly warped copy of image
+pub fn add_warped_image_to_diagnostics<R:Rng>(stats:&mut diagnostics::BitStatistics,
+                                              image:&GrayImage, config:&Config,
+                                              range:&WarpRange, rng:&mut R) -> Result<()> {
+    let tests = &config.rbrief_test_set;
+    let describe = |im:&rbrief::KernelImage, (x, y, angle):(u32, u32, f32)| if config.upright {
+        tests.describe_upright_prepared(im, x, y)
+    } else {
+        tests.describe_prepared(im, x, y, angle)
+    };
+    if let Some((pyramid, warped_pyramid, groups)) = warped_pairs(image, config, range, rng)? {
+        let levels = DescriptionLevels::new(&pyramid, config, tests.kernel());
+        let warped_levels = DescriptionLevels::new(&warped_pyramid, config, tests.kernel());
+        for (&(level, warped_level), pairs) in groups.iter() {
+            for (a, b) in oriented_pairs(&levels, &warped_levels, (level, warped_level), pairs, config) {
+                let da = describe(levels.level(level), a);
+                let db = describe(warped_levels.level(warped_level), b);
+                if let (Some(da), Some(db)) = (da, db) {
+                    stats.add(da);
+                    stats.add_matched(da, db);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// the patch layout a test set was trained for
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PatchGeometry {
+    pub half_width: u32,
+    pub max_offset: i32,
+    pub radius: u32,
+    pub kernel: rbrief::Kernel
+}
+
+impl PatchGeometry {
+    pub fn new(kernel:rbrief::Kernel) -> PatchGeometry {
+        PatchGeometry {
+            half_width: rbrief::HWIDTH,
+            max_offset: rbrief::MAX,
+            radius: kernel.radius(),
+            kernel: kernel
+        }
+    }
+}
+
+// everything needed to reproduce a training run, saved alongside the test set
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TrainingManifest {
+    pub crate_version: String,
+    pub seed: u64,
+    pub supervised: bool,
+    pub selection: String,
+    pub images: Vec<String>,
+    pub num_features: usize,
+    pub fast_threshold: u8,
+    pub num_pyramid_levels: u32,
+    pub orientation_radius: u32,
+    pub upright: bool,
+    pub border_policy: BorderPolicy,
+    pub patch: PatchGeometry,
+    #[serde(default)]
+    pub resize: dataset::ResizePolicy,
+    // the seed of each run merged into this one, in the order their images are listed
+    #[serde(default)]
+    pub merged_seeds: Vec<u64>,
+    // the keypoints accumulated from the images
+    #[serde(default)]
+    pub samples: usize
+}
+
+impl TrainingManifest {
+    pub fn new(config:&Config, kernel:rbrief::Kernel, seed:u64) -> TrainingManifest {
+        TrainingManifest {
+            crate_version: env!("CARGO_PKG_VERSION").to_string(),
+            seed: seed,
+            supervised: false,
+            selection: String::new(),
+            images: Vec::new(),
+            num_features: config.num_features,
+            fast_threshold: config.fast_threshold,
+            num_pyramid_levels: config.num_pyramid_levels,
+            orientation_radius: config.orientation_radius,
+            upright: config.upright,
+            border_policy: config.border_policy,
+            patch: PatchGeometry::new(kernel),
+            resize: dataset::ResizePolicy::default(),
+            merged_seeds: Vec::new(),
+            samples: 0
+        }
+    }
+
+    // runs can only be combined if they found and described keypoints the same way
+    pub fn check_compatible(&self, other:&TrainingManifest) -> Result<()> {
+        let differences:Vec<&str> = [
+            ("supervised", self.supervised == other.supervised),
+            ("num_features", self.num_features == other.num_features),
+            ("fast_threshold", self.fast_threshold == other.fast_threshold),
+            ("num_pyramid_levels", self.num_pyramid_levels == other.num_pyramid_levels),
+            ("orientation_radius", self.orientation_radius == other.orientation_radius),
+            ("upright", self.upright == other.upright),
+            ("border_policy", self.border_policy == other.border_policy),
+            ("patch", self.patch == other.patch),
+            ("resize", self.resize == other.resize)
+        ].iter()
+            .filter(|d| !d.1)
+            .map(|d| d.0)
+            .collect();
+        if differences.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Incompatible(format!("training runs differ in {}", differences.join(", "))))
+        }
+    }
+
+    // add the images and seed of a run whose trainer was merged into this one's
+    pub fn merge(&mut self, other:&TrainingManifest) -> Result<()> {
+        self.check_compatible(other)?;
+        if self.merged_seeds.is_empty() {
+            self.merged_seeds.push(self.seed);
+        }
+        if other.merged_seeds.is_empty() {
+            self.merged_seeds.push(other.seed);
+        } else {
+            self.merged_seeds.extend_from_slice(&other.merged_seeds);
+        }
+        self.images.extend_from_slice(&other.images);
+        self.samples += other.samples;
+        Ok(())
+    }
+
+    // written alongside then renamed, as it's saved with each training checkpoint
+    pub fn save(&self, filename:&str) -> Result<()> {
+        let serialized = serde_json::to_string_pretty(self)?;
+        let partial = format!("{}.tmp", filename);
+        std::fs::write(&partial, serialized)?;
+        std::fs::rename(&partial, filename)?;
+        Ok(())
+    }
+
+    pub fn load(filename:&str) -> Result<TrainingManifest> {
+        let serialized = std::fs::read_to_string(filename)?;
+        Ok(serde_json::from_str(&serialized)?)
     }
 }
 
+// a 128x96 chequerboard of 16 pixel squares, with corners right up to its border
+#[cfg(test)]
+pub(crate) fn checkerboard() -> GrayImage {
+    GrayImage::from_fn(128, 96, |x, y| Luma([if (x / 16 + y / 16) % 2 == 0 { 32u8 } else { 224u8 }]))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use image::{imageops, ImageBuffer, Luma};
     use imageproc::{geometric_transformations};
     use more_asserts::*;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
 
     #[test]
     fn test_pyramid() {
         let image = ImageBuffer::from_pixel(8, 8, Luma([128u8]));
-        let pyramid = Pyramid::new(&image, 4);
+        let pyramid = Pyramid::new(&image, 4).unwrap();
         assert_eq!(pyramid.images.len(), 4);
         assert_eq!(pyramid.images[3].dimensions(), (1, 1));
         assert_eq!(*pyramid.images[3].get_pixel(0, 0), Luma([128u8]));
@@ -368,5 +1015,262 @@ mod tests {
         let angle = orientation(&im_r, 10, 10, 3);
         assert_eq!(angle, 3.0 * pi_over_4);
       }
-}
 
+    #[test]
+    fn test_circular_extents() {
+        assert_eq!(circular_extents(0.5), vec![0]);
+        assert_eq!(circular_extents(2.5), vec![1, 2, 2, 2, 1]);
+        assert_eq!(circular_extents(15.5).len(), 31);
+    }
+
+    #[test]
+    fn test_orientation_confidence() {
+        let image = ImageBuffer::from_pixel(40, 40, Luma([128u8]));
+        let patch = OrientationPatch::new(15);
+        let (_, confidence) = patch.centroid(&image, 20, 20);
+        assert_eq!(confidence, 0.0);
+        let mut image = GrayImage::new(40, 40);
+        imageops::horizontal_gradient(&mut image, &Luma([0]), &Luma([255]));
+        let (angle, confidence) = patch.centroid(&image, 20, 20);
+        assert_eq!(angle, 0.0);
+        assert_gt!(confidence, 1.0);
+        let (_, confidence) = patch.centroid(&image, 30, 20);
+        assert_eq!(confidence, 0.0);
+    }
+
+    #[test]
+    fn test_pad_image() {
+        let image = GrayImage::from_fn(3, 1, |x, _| Luma([x as u8]));
+        let reflected = pad_image(&image, 2, BorderPolicy::Reflect);
+        assert_eq!(reflected.dimensions(), (7, 5));
+        let row:Vec<u8> = (0..7).map(|x| reflected.get_pixel(x, 0)[0]).collect();
+        assert_eq!(row, vec![2, 1, 0, 1, 2, 1, 0]);
+        let replicated = pad_image(&image, 2, BorderPolicy::Replicate);
+        let row:Vec<u8> = (0..7).map(|x| replicated.get_pixel(x, 4)[0]).collect();
+        assert_eq!(row, vec![0, 0, 0, 1, 2, 2, 2]);
+    }
+
+    #[test]
+    fn test_border_policy() {
+        let image = checkerboard();
+        let mut config = Config::default();
+        config.num_pyramid_levels = 1;
+        for &policy in [BorderPolicy::Drop, BorderPolicy::Reflect, BorderPolicy::Replicate].iter() {
+            config.border_policy = policy;
+            let corners = find_multiscale_features(&image, &config).unwrap();
+            assert_gt!(corners.len(), 0);
+            assert!(corners.iter().all(|c| c.descriptor.is_some()));
+            let near_border = corners.iter().any(|c| c.corner.x < rbrief::RADIUS);
+            assert_eq!(near_border, policy != BorderPolicy::Drop);
+        }
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn test_thread_count() {
+        // the same features in the same order however many threads find them
+        let image = GrayImage::from_fn(200, 150, |x, y|
+            Luma([if (x / 12 + y / 12) % 2 == 0 { (x * 7 % 64) as u8 } else { 192 + (y * 5 % 64) as u8 }]));
+        let config = Config::default();
+        let found = |threads| rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap()
+            .install(|| find_multiscale_features(&image, &config).unwrap())
+            .iter()
+            .map(|c| (c.corner.x, c.corner.y, c.level, c.angle, c.descriptor))
+            .collect::<Vec<_>>();
+        let one = found(1);
+        assert!(one.len() > 100);
+        assert_eq!(found(4), one);
+    }
+
+    #[test]
+    fn test_match_indices() {
+        let a = find_multiscale_features(&checkerboard(), &Config::default()).unwrap();
+        // the same corners found again, which are equal but not in a
+        let copy = find_multiscale_features(&checkerboard(), &Config::default()).unwrap();
+        assert!(a.len() > 3);
+        let matches = vec![Some(&a[2]), None, Some(&a[0]), Some(&copy[1]), Some(&a[a.len() - 1])];
+        assert_eq!(match_indices(&a, &matches), vec![Some(2), None, Some(0), None, Some(a.len() - 1)]);
+    }
+
+    #[test]
+    fn test_degenerate_images() {
+        let config = Config::default();
+        assert!(matches!(find_multiscale_features(&GrayImage::new(0, 0), &config),
+                         Err(Error::EmptyImage(0, 0))));
+        assert!(Pyramid::new(&GrayImage::new(16, 0), 4).is_err());
+        // too small for the orientation patch or for FAST, and featureless
+        for &(w, h) in [(1, 1), (5, 3), (2, 200), (20, 20)].iter() {
+            let image = GrayImage::from_fn(w, h, |x, y| Luma([((x * 37 + y * 91) % 256) as u8]));
+            for &policy in [BorderPolicy::Drop, BorderPolicy::Reflect].iter() {
+                let config = Config { border_policy: policy, ..Config::default() };
+                find_multiscale_features(&image, &config).unwrap();
+            }
+        }
+        let flat = GrayImage::from_pixel(64, 64, Luma([128u8]));
+        let corners = find_multiscale_features(&flat, &config).unwrap();
+        assert!(corners.is_empty());
+        assert!(find_matches(&corners, &corners, &config).unwrap().is_empty());
+        let no_tables = Config { lsh_k_l: (4, 0), ..Config::default() };
+        assert!(find_matches(&corners, &corners, &no_tables).is_err());
+        // a pruned test set makes descriptors too short to sample 16 bits from
+        let mut pruned = rbrief::TestSet::trained();
+        pruned.set.truncate(8);
+        let short = Config { rbrief_test_set: rbrief::RBrief::from_test_set(pruned), lsh_k_l: (16, 4), ..Config::default() };
+        assert!(find_matches(&corners, &corners, &short).is_err());
+        assert!(match_descriptors(&[], &[], &short).is_err());
+
+        let mut trainer = rbrief::Trainer::new();
+        add_image_to_trainer(&mut trainer, &flat, &config).unwrap();
+        assert!(add_image_to_trainer(&mut trainer, &GrayImage::new(0, 4), &config).is_err());
+        assert!(matches!(trainer.make_test_set(), Err(Error::NoSamples)));
+    }
+
+    #[test]
+    fn test_supervised_identity_warp() {
+        let image = checkerboard();
+        let mut config = Config::default();
+        config.num_pyramid_levels = 1;
+        let identity = WarpRange {
+            max_rotation: 0.0,
+            min_scale: 1.0,
+            max_scale: 1.0,
+            max_perspective: 0.0,
+            noise_stddev: 0.0
+        };
+        let mut trainer = rbrief::SupervisedTrainer::new();
+        add_image_to_supervised_trainer(&mut trainer, &image, &config, &identity,
+                                        &mut StdRng::seed_from_u64(0)).unwrap();
+        assert_gt!(trainer.samples(), 0);
+        assert!(trainer.flip_rates().iter().all(|&f| f == 0.0));
+    }
+
+    #[test]
+    fn test_supervised_rotation() {
+        // overlapping rectangles, so corners are unlike the patches around them
+        let mut rng = StdRng::seed_from_u64(3);
+        let mut image = GrayImage::from_pixel(160, 120, Luma([128u8]));
+        for _ in 0..40 {
+            let rect = imageproc::rect::Rect::at(rng.gen_range(0, 150), rng.gen_range(0, 110))
+                .of_size(rng.gen_range(8, 40), rng.gen_range(8, 40));
+            imageproc::drawing::draw_filled_rect_mut(&mut image, rect, Luma([rng.gen_range(0, 256) as u8]));
+        }
+        let mut config = Config::default();
+        config.num_pyramid_levels = 1;
+        let rotation = WarpRange {
+            max_rotation: 0.5,
+            min_scale: 1.0,
+            max_scale: 1.0,
+            max_perspective: 0.0,
+            noise_stddev: 0.0
+        };
+        let mut trainer = rbrief::SupervisedTrainer::new();
+        assert!(trainer.flip_rates().iter().all(|&f| f == 0.0));
+        for _ in 0..4 {
+            add_image_to_supervised_trainer(&mut trainer, &image, &config, &rotation, &mut rng).unwrap();
+        }
+        assert_gt!(trainer.samples(), 0);
+        let flip_rates = trainer.flip_rates();
+        let mean_flip_rate = flip_rates.iter().sum::<f32>() / flip_rates.len() as f32;
+        assert_gt!(mean_flip_rate, 0.0);
+        // the selected tests flip less often than tests do on the whole
+        let (tests, report) = trainer.make_test_set().unwrap();
+        assert_eq!(tests.set.len(), report.tests.len());
+        let selected:Vec<f32> = report.tests.iter().map(|t| t.flip_rate.unwrap()).collect();
+        assert_lt!(selected.iter().sum::<f32>() / selected.len() as f32, mean_flip_rate);
+    }
+
+    #[test]
+    fn test_warped_diagnostics() {
+        let image = checkerboard();
+        let mut config = Config::default();
+        config.num_pyramid_levels = 1;
+        config.upright = true;
+        let identity = WarpRange {
+            max_rotation: 0.0,
+            min_scale: 1.0,
+            max_scale: 1.0,
+            max_perspective: 0.0,
+            noise_stddev: 0.0
+        };
+        let mut stats = diagnostics::BitStatistics::new(128);
+        add_warped_image_to_diagnostics(&mut stats, &image, &config, &identity,
+                                        &mut StdRng::seed_from_u64(0)).unwrap();
+        let d = stats.diagnostics();
+        assert_gt!(d.matched, 0);
+        assert!(d.flip_rate.unwrap().iter().all(|&f| f == 0.0));
+    }
+
+    #[test]
+    fn test_config_files() {
+        let config = Config::default();
+        let serialized = config.to_toml().unwrap();
+        assert!(serialized.contains("rbrief_test_set = \"trained\""));
+        let loaded = Config::from_toml(&serialized).unwrap();
+        assert_eq!(loaded.num_features, config.num_features);
+        assert_eq!(loaded.lsh_k_l, config.lsh_k_l);
+
+        let partial = Config::from_toml("num_features = 100\nborder_policy = \"Reflect\"\n\
+                                         rbrief_test_set = { pattern = \"Gaussian\", seed = 3 }\n").unwrap();
+        assert_eq!(partial.num_features, 100);
+        assert_eq!(partial.border_policy, BorderPolicy::Reflect);
+        assert_eq!(partial.fast_threshold, config.fast_threshold);
+        assert_eq!(partial.rbrief_test_set.test_set(0.0).set,
+                   rbrief::TestSet::from_pattern(rbrief::Pattern::Gaussian, 3).set);
+        // a set other than the trained one is written out in full
+        let json = serde_json::to_string(&partial).unwrap();
+        assert!(json.contains("encoded"));
+        let reloaded = Config::from_json(&json).unwrap();
+        assert_eq!(reloaded.rbrief_test_set.test_set(0.0).set, partial.rbrief_test_set.test_set(0.0).set);
+        let toml = partial.to_toml().unwrap();
+        assert_eq!(Config::from_toml(&toml).unwrap().num_features, 100);
+
+        assert!(Config::from_toml("num_pyramid_levels = 0").is_err());
+        assert!(Config::from_toml("lsh_max_distance = 200").is_err());
+        assert!(Config::from_json("{\"rbrief_test_set\": {\"file\": \"missing.json\"}}").is_err());
+
+        // a test set file is found beside the config that names it
+        let dir = std::env::temp_dir().join("image_processing_test_config_files");
+        std::fs::create_dir_all(&dir).unwrap();
+        rbrief::TestSet::from_pattern(rbrief::Pattern::Uniform, 5).save(dir.join("set.json").to_str().unwrap()).unwrap();
+        let filename = dir.join("config.toml");
+        std::fs::write(&filename, "rbrief_test_set = { file = \"set.json\" }\n").unwrap();
+        let loaded = Config::load(filename.to_str().unwrap()).unwrap();
+        assert_eq!(loaded.rbrief_test_set.test_set(0.0).set,
+                   rbrief::TestSet::from_pattern(rbrief::Pattern::Uniform, 5).set);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_config_presets() {
+        for name in PRESETS.iter() {
+            let config = Config::preset(name).unwrap();
+            config.validate().unwrap();
+        }
+        assert!(Config::preset("slow").is_err());
+    }
+
+    #[test]
+    fn test_training_manifest() {
+        let config = Config::default();
+        let mut manifest = TrainingManifest::new(&config, rbrief::Kernel::default(), 42);
+        manifest.images.push("a.png".to_string());
+        assert_eq!(manifest.patch.radius, rbrief::RADIUS);
+        let serialized = serde_json::to_string(&manifest).unwrap();
+        let loaded:TrainingManifest = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(loaded, manifest);
+
+        let mut other = TrainingManifest::new(&config, rbrief::Kernel::default(), 7);
+        other.images.push("b.png".to_string());
+        manifest.merge(&other).unwrap();
+        assert_eq!(manifest.merged_seeds, vec![42, 7]);
+        assert_eq!(manifest.images, vec!["a.png".to_string(), "b.png".to_string()]);
+        // runs that found keypoints differently can't be merged
+        let upright = Config { upright: true, num_pyramid_levels: 2, ..Config::default() };
+        let other = TrainingManifest::new(&upright, rbrief::Kernel::default(), 8);
+        match manifest.merge(&other) {
+            Err(Error::Incompatible(e)) => assert!(e.ends_with("num_pyramid_levels, upright")),
+            r => panic!("expected incompatible runs, not {:?}", r)
+        }
+        assert_eq!(manifest.images.len(), 2);
+    }
+}
@@ -1,16 +1,28 @@
 extern crate nalgebra as na;
-use image::{Rgba, Luma, RgbaImage, imageops};
+use image::{Rgba, Luma, GrayImage, RgbaImage};
 use imageproc::{drawing, geometric_transformations};
 //use std::time::SystemTime;
 //use std::collections::HashMap;
 //use itertools::Itertools;
-use std::env;
-use std::ops::IndexMut;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use serde::Serialize;
+use serde_json::json;
+use std::collections::HashSet;
+use std::error::Error;
 use std::fs;
-use std::io;
-use std::path::PathBuf;
-use image_processing::{Config, Corner, add_image_to_trainer, find_multiscale_features, find_matches};
-use image_processing::rbrief;
+use std::ops::IndexMut;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use image_processing::{BorderPolicy, Config, Corner, PRESETS, TrainingManifest, WarpRange, add_image_to_trainer,
+                       add_image_to_supervised_trainer, add_warped_image_to_diagnostics,
+                       find_multiscale_features, find_matches, match_indices, matched_points};
+use image_processing::{batch, colmap, dataset, diagnostics, npy, opencv, rbrief, registration, visualise};
+use image_processing::features::FeatureSet;
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
 
 fn draw_features(image:&mut RgbaImage, corners:&Vec<Corner>) {
     let blue = Rgba([0u8, 0u8, 255u8, 128u8]);
@@ -18,7 +30,7 @@ fn draw_features(image:&mut RgbaImage, corners:&Vec<Corner>) {
     for corner in corners.iter() {
         let s = 1 << corner.level;
         let p = ((corner.corner.x * s) as i32, (corner.corner.y * s) as i32);
-        drawing::draw_hollow_circle_mut(image, p, 3 * s as i32, blue);  
+        drawing::draw_hollow_circle_mut(image, p, 3 * s as i32, blue);
         let ln = ((s as f32) * 3.0 * corner.angle.cos(), (s as f32) * 3.0 * corner.angle.sin());
         let line_start = (p.0 as f32 + ln.0, p.1 as f32 + ln.1);
         let line_end = (p.0 as f32 - ln.0, p.1 as f32 - ln.1);
@@ -43,6 +55,16 @@ fn draw_matches(image:&mut RgbaImage, corners:&Vec<Corner>, matches:&Vec<Option<
     }
 }
 
+fn to_rgba(image:&GrayImage) -> RgbaImage {
+    // make a grey -> RGB pallete
+    let mut palette = [(0u8, 0u8, 0u8); 256];
+    for i in 0..256 {
+        let g = i as u8;
+        palette[i] = (g, g, g);
+    }
+    image.clone().expand_palette(&palette, None)
+}
+
 fn expected_location(w:u32, h:u32, theta:f32, x:u32, y:u32) -> (u32, u32) {
     let rot = na::Rotation2::new(-theta);
     let trans1 = na::Translation2::new(-(w as f32 / 2.0), -(h as f32 / 2.0));
@@ -52,33 +74,45 @@ fn expected_location(w:u32, h:u32, theta:f32, x:u32, y:u32) -> (u32, u32) {
     (expected.x.round() as u32, expected.y.round() as u32)
 }
 
+#[derive(Serialize)]
+struct DistanceStats {
+    count: usize,
+    mean: f32,
+    stddev: f32
+}
+
+#[derive(Serialize)]
+struct MatchStats {
+    features: usize,
+    matches: usize,
+    true_positives: DistanceStats,
+    false_positives: DistanceStats,
+    // the hamming distance threshold that would maximise true - false positives
+    best_threshold: usize
+}
+
 fn match_stats(corners:&Vec<Corner>, matches:&Vec<Option<&Corner>>,
-               transform: (u32, u32, f32)) {
+               transform: (u32, u32, f32)) -> MatchStats {
     let (w, h, theta) = transform;
 
     let mut tp_distances = Vec::<u32>::new();
     let mut fp_distances = Vec::<u32>::new();
 
     for (corner, omatch) in corners.iter().zip(matches.iter()) {
-        if let Some(m) = omatch {
-            let a = corner.descriptor.unwrap();
-            let b = m.descriptor.unwrap();
-            let d = hamming_lsh::hamming_distance(a, b);
+        // matches are only made between corners with descriptors
+        if let (Some(m), Some(a)) = (omatch, corner.descriptor) {
+            let d = m.descriptor.map_or(128, |b| hamming_lsh::hamming_distance(a, b));
             let e = expected_location(w, h, theta, corner.corner.x, corner.corner.y);
-            let true_positive = (e.0 as i32 - m.corner.x as i32).abs() < 2 
+            let true_positive = (e.0 as i32 - m.corner.x as i32).abs() < 2
                              && (e.1 as i32 - m.corner.y as i32).abs() < 2;
             let stats = if true_positive { &mut tp_distances } else { &mut fp_distances };
             stats.push(d);
         }
     }
-    let mut histogram = vec![(0, 0); 128];
-    println!("true positives:");
-    calc(&tp_distances, &mut histogram, true);
-    println!("false positives:");
-    calc(&fp_distances, &mut histogram, false);
-    println!("features: {}, matches: {}",
-             corners.len(), tp_distances.len() + fp_distances.len());
- 
+    let mut histogram = vec![(0, 0); 129];
+    let true_positives = calc(&tp_distances, &mut histogram, true);
+    let false_positives = calc(&fp_distances, &mut histogram, false);
+
     let cumulative:Vec<(u32, u32)> =
                         histogram.iter()
                         .scan((0, 0), |s, (a, b)| {
@@ -97,149 +131,869 @@ fn match_stats(corners:&Vec<Corner>, matches:&Vec<Option<&Corner>>,
                     .map(|(idx, _)| idx)
                     .unwrap();
 
-    println!("an hamming distance threshold of {} would maximise true - false positives",
-             threshold);
+    return MatchStats {
+        features: corners.len(),
+        matches: tp_distances.len() + fp_distances.len(),
+        true_positives: true_positives,
+        false_positives: false_positives,
+        best_threshold: threshold
+    };
 
-    fn calc(distances:&Vec<u32>, histogram:&mut Vec<(u32, u32)>, i:bool) {
+    fn calc(distances:&Vec<u32>, histogram:&mut Vec<(u32, u32)>, i:bool) -> DistanceStats {
         for d in distances.iter() {
-            let mut count = histogram.index_mut(*d as usize);
+            let count = histogram.index_mut(*d as usize);
             if i { count.0 += 1; } else { count.1 += 1};
         }
         let mean = distances.iter().fold(0.0, |s, d| s + *d as f32) / distances.len() as f32;
         let var = distances.iter().fold(0.0, |v, d| v + (*d as f32 - mean) * (*d as f32 - mean)) / distances.len() as f32;
-        let stddev = var.sqrt();
-        println!("count: {}, mean: {}, stddev: {}", distances.len(), mean, stddev);
+        DistanceStats {
+            count: distances.len(),
+            mean: mean,
+            stddev: var.sqrt()
+        }
     }
 }
 
-fn files(dir: &str) -> Result<Vec<PathBuf>, io::Error> {
-    Ok(fs::read_dir(dir)?
-        .into_iter()
-        .filter(|r| r.is_ok()) // Get rid of Err variants for Result<DirEntry>
-        .map(|r| r.unwrap().path()) // This is safe, since we only have the Ok variants
-        .filter(|r| r.is_file()) // Filter out non-files
-        .collect())
+fn evaluate_rotation(src_image:&GrayImage, theta:f32, config:&Config) -> Result<MatchStats> {
+    let (w, h) = src_image.dimensions();
+    let im_r = geometric_transformations::rotate_about_center(src_image,
+                            theta,
+                            geometric_transformations::Interpolation::Nearest,
+                            Luma([0]));
+    let corners = find_multiscale_features(src_image, config)?;
+    let corners_r = find_multiscale_features(&im_r, config)?;
+    let matches = find_matches(&corners, &corners_r, config)?;
+    Ok(match_stats(&corners_r, &matches, (w, h, theta)))
 }
 
-fn train_rbrief(dir_name:&str, count:usize) {
-    // accumulate a bit array for every pair in 31x31 rect
-    // for each image
-    //   find features
-    //   for every feature
-    //     make integral image around point
-    //     for every pair in 31x31
-    //       rbrief test
-    //       push result into 1 bit of u8 array
-    // then perform greedy algorithm described in paper where 
-    // mean = hamming::weight(t) / num_images
-    // correlation = sum_over_R(hamming::distance(Ri, t))
+fn selection(name:&str) -> Box<dyn rbrief::Selection> {
+    match name {
+        "minmax" => Box::new(rbrief::MinMaxGreedy::default()),
+        "incremental" => Box::new(rbrief::IncrementalGreedyThreshold::default()),
+        _ => Box::new(rbrief::GreedyThreshold::default())
+    }
+}
 
-    println!("training rBrief descriptor test set");
-    let config = Config::default();
-    let mut trainer = rbrief::Trainer::new();
+// use the given seed, or pick one that will be recorded in the manifest
+fn seed(arg:Option<&str>) -> u64 {
+    let seed = arg.and_then(|s| s.parse().ok()).unwrap_or_else(|| rand::thread_rng().gen());
+    eprintln!("using seed {}", seed);
+    seed
+}
 
-    println!("using {} images from {}", count, dir_name);
+// the path alongside output with the given suffix, e.g. out.json -> out.report.json
+fn output_path(output:&str, suffix:&str) -> String {
+    Path::new(output).with_extension(suffix).to_string_lossy().into_owned()
+}
 
-    let mut num = 0;
-    if let Ok(entries) = files(dir_name) {
-        println!("{} files in folder", entries.len());
-        for path in entries.iter() {
-            println!("reading {:?}", path);
-            
-            // open the source image as greyscale
-            if let Ok(src_image) = image::open(path) {
-                let src_image = src_image.into_luma8();
-
-                // resize to ~ 640x480
-                let (mut w, mut h) = src_image.dimensions();
-                h = h * 640 / w;
-                w = 640;
-                let image = imageops::resize(&src_image,
-                    w, h, imageops::FilterType::CatmullRom);
-                println!("adding to trainer");
-                add_image_to_trainer(&mut trainer, &image, &config);
-                num += 1;
-                if num == count {
-                    break;
-                }
-            }
+// write value as JSON to the --json file, or stdout
+fn write_json<T:Serialize>(value:&T, m:&ArgMatches) -> Result<()> {
+    let serialized = serde_json::to_string_pretty(value)?;
+    match m.value_of("json") {
+        Some(filename) => fs::write(filename, serialized)?,
+        None => println!("{}", serialized)
+    }
+    Ok(())
+}
+
+fn json_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("json")
+        .long("json")
+        .takes_value(true)
+        .help("write the JSON result to this file instead of stdout")
+}
+
+fn resize_arg<'a, 'b>(default:&'a str) -> Arg<'a, 'b> {
+    Arg::with_name("resize")
+        .long("resize")
+        .takes_value(true)
+        .default_value(default)
+        .help("original, width:<w>, max:<size> or scale:<s>")
+}
+
+fn open_image(m:&ArgMatches, name:&str) -> Result<GrayImage> {
+    let path = m.value_of(name).unwrap();
+    let resize = dataset::ResizePolicy::parse(m.value_of("resize").unwrap())?;
+    dataset::load_image(Path::new(path), resize)
+        .map_err(|e| format!("couldn't open {}: {}", path, e).into())
+}
+
+// arguments choosing a set of images
+fn dataset_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("inputs")
+            .help("folders of images")
+            .multiple(true),
+        Arg::with_name("list")
+            .long("list")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("a file listing images, one per line"),
+        Arg::with_name("recursive")
+            .short("r")
+            .long("recursive")
+            .help("include images in subfolders"),
+        Arg::with_name("ext")
+            .long("ext")
+            .takes_value(true)
+            .use_delimiter(true)
+            .default_value("png,jpg,jpeg,bmp,tif,tiff,pgm,ppm")
+            .help("image file extensions to include"),
+        resize_arg("width:640"),
+        Arg::with_name("limit")
+            .long("limit")
+            .takes_value(true)
+            .help("use at most this many images")
+    ]
+}
+
+fn dataset_from_args(m:&ArgMatches) -> Result<dataset::Dataset> {
+    let extensions:Vec<String> = m.values_of("ext").map_or(Vec::new(), |v| v.map(String::from).collect());
+    let dirs:Vec<&str> = m.values_of("inputs").map_or(Vec::new(), |v| v.collect());
+    let mut images = dataset::Dataset::from_dirs(&dirs, m.is_present("recursive"), &extensions)?;
+    for list in m.values_of("list").into_iter().flatten() {
+        images.append(dataset::Dataset::from_file_list(list)?);
+    }
+    if images.is_empty() {
+        return Err("no images found".into());
+    }
+    Ok(images)
+}
+
+// arguments overriding the default Config
+fn config_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("config").long("config").takes_value(true).conflicts_with("preset")
+            .help("toml or json config file, which the options below override"),
+        Arg::with_name("preset").long("preset").takes_value(true)
+            .possible_values(&PRESETS),
+        Arg::with_name("features").long("features").takes_value(true)
+            .help("number of features per image"),
+        Arg::with_name("fast-threshold").long("fast-threshold").takes_value(true),
+        Arg::with_name("levels").long("levels").takes_value(true)
+            .help("number of pyramid levels"),
+        Arg::with_name("orientation-radius").long("orientation-radius").takes_value(true),
+        Arg::with_name("upright").long("upright")
+            .help("don't estimate keypoint orientation"),
+        Arg::with_name("border").long("border").takes_value(true)
+            .possible_values(&["drop", "reflect", "replicate"])
+            .help("how keypoints near the image border are described"),
+        Arg::with_name("test-set").long("test-set").takes_value(true)
+            .help("rBRIEF test set file to use instead of the trained set"),
+        Arg::with_name("kernel").long("kernel").takes_value(true)
+            .help("sampling kernel, box:<width> or gaussian:<sigma>"),
+        Arg::with_name("lsh-k").long("lsh-k").takes_value(true),
+        Arg::with_name("lsh-l").long("lsh-l").takes_value(true),
+        Arg::with_name("max-distance").long("max-distance").takes_value(true)
+            .help("largest hamming distance of a match")
+    ]
+}
+
+fn parse_arg<T:std::str::FromStr>(m:&ArgMatches, name:&str) -> Result<Option<T>> {
+    match m.value_of(name) {
+        Some(v) => v.parse().map(Some).map_err(|_| format!("invalid --{} {}", name, v).into()),
+        None => Ok(None)
+    }
+}
+
+fn config_from_args(m:&ArgMatches, config:Config) -> Result<Config> {
+    let mut config = match (m.value_of("config"), m.value_of("preset")) {
+        (Some(filename), _) => Config::load(filename)?,
+        (None, Some(name)) => Config::preset(name)?,
+        (None, None) => config
+    };
+    if let Some(v) = parse_arg(m, "features")? { config.num_features = v; }
+    if let Some(v) = parse_arg(m, "fast-threshold")? { config.fast_threshold = v; }
+    if let Some(v) = parse_arg(m, "levels")? { config.num_pyramid_levels = v; }
+    if let Some(v) = parse_arg(m, "orientation-radius")? { config.orientation_radius = v; }
+    if let Some(v) = parse_arg(m, "lsh-k")? { config.lsh_k_l.0 = v; }
+    if let Some(v) = parse_arg(m, "lsh-l")? { config.lsh_k_l.1 = v; }
+    if let Some(v) = parse_arg(m, "max-distance")? { config.lsh_max_distance = v; }
+    config.upright = config.upright || m.is_present("upright");
+    match m.value_of("border") {
+        Some("reflect") => config.border_policy = BorderPolicy::Reflect,
+        Some("replicate") => config.border_policy = BorderPolicy::Replicate,
+        Some(_) => config.border_policy = BorderPolicy::Drop,
+        None => ()
+    }
+    if m.is_present("test-set") || m.is_present("kernel") {
+        let mut set = match m.value_of("test-set") {
+            Some(filename) => rbrief::TestSet::load(filename)?,
+            None => rbrief::TestSet::trained()
+        };
+        if let Some(kernel) = m.value_of("kernel") {
+            set.kernel = rbrief::Kernel::parse(kernel)?;
         }
-    } else {
-        println!("couldn't read {}", dir_name);
-        return;
+        config.rbrief_test_set = rbrief::RBrief::from_test_set(set);
     }
-    if num == 0 {
-        println!("didn't find any images");
-        return;
+    config.validate()?;
+    Ok(config)
+}
+
+fn detect(m:&ArgMatches) -> Result<()> {
+    let image = open_image(m, "image")?;
+    let config = config_from_args(m, Config::default())?;
+    let corners = find_multiscale_features(&image, &config)?;
+    if let Some(filename) = m.value_of("draw") {
+        let mut dst = to_rgba(&image);
+        draw_features(&mut dst, &corners);
+        dst.save(filename)?;
+    }
+    // the output can be loaded back with FeatureSet::load
+    let mut features = FeatureSet::new(image.width(), image.height(), &config, &corners)?;
+    features.image = m.value_of("image").unwrap_or("").to_string();
+    if let Some(filename) = m.value_of("binary") {
+        features.save_binary(filename)?;
+    }
+    if let Some(filename) = m.value_of("npz") {
+        npy::save_npz(filename, &features.features)?;
     }
-    trainer.make_test_set().save("trained_test_set.json").expect("failed to save trained set");
+    if let Some(filename) = m.value_of("opencv") {
+        opencv::save_yaml(filename, &features.features)?;
+    }
+    write_json(&features, m)
 }
 
-fn main() {
-    println!("Hello, world!");
+#[derive(Serialize)]
+struct Match {
+    // indices into the features of each image
+    a: usize,
+    b: usize,
+    distance: u32,
+    a_position: (f32, f32),
+    b_position: (f32, f32)
+}
+
+fn matches_json(a:&[Corner], b:&[Corner], matches:&[Option<&Corner>]) -> Vec<Match> {
+    b.iter().enumerate().zip(match_indices(a, matches))
+        .filter_map(|((j, cb), i)| {
+            let i = i?;
+            Some(Match {
+                a: i,
+                b: j,
+                distance: hamming_lsh::hamming_distance(a[i].descriptor?, cb.descriptor?),
+                a_position: a[i].position(),
+                b_position: cb.position()
+            })
+        })
+        .collect()
+}
 
-    let args:Vec<String> = env::args().collect();
-    if args.len() == 4 && args[1] == "train" {
-        train_rbrief(&args[2], args[3].parse().unwrap_or(1));
+fn match_images(m:&ArgMatches) -> Result<()> {
+    let image_a = open_image(m, "a")?;
+    let image_b = open_image(m, "b")?;
+    let config = config_from_args(m, Config::default())?;
+    let a = find_multiscale_features(&image_a, &config)?;
+    let b = find_multiscale_features(&image_b, &config)?;
+    let matches = find_matches(&a, &b, &config)?;
+    if let Some(filename) = m.value_of("draw") {
+        let mut dst = to_rgba(&image_b);
+        draw_matches(&mut dst, &b, &matches);
+        dst.save(filename)?;
     }
+    write_json(&json!({
+        "a": { "image": m.value_of("a"), "features": a.len() },
+        "b": { "image": m.value_of("b"), "features": b.len() },
+        "matches": matches_json(&a, &b, &matches)
+    }), m)
+}
 
-    // make a grey -> RGB pallete
-    let mut palette = [(0u8, 0u8, 0u8); 256];
-    for i in 0..255 {
-        let g = i as u8;
-        palette[i] = (g, g, g);
+// find the homography taking b to a from the features matched between them
+fn register(m:&ArgMatches) -> Result<()> {
+    let image_a = open_image(m, "a")?;
+    let image_b = open_image(m, "b")?;
+    let config = config_from_args(m, Config::default())?;
+    let a = find_multiscale_features(&image_a, &config)?;
+    let b = find_multiscale_features(&image_b, &config)?;
+    let matches = find_matches(&a, &b, &config)?;
+    let pairs = matched_points(&b, &matches);
+    let points:Vec<registration::PointPair> = pairs.iter().map(|p| p.1).collect();
+    let mut params = registration::RansacParams::default();
+    if let Some(v) = parse_arg(m, "iterations")? { params.iterations = v; }
+    if let Some(v) = parse_arg(m, "threshold")? { params.threshold = v; }
+    let mut rng = ChaCha8Rng::seed_from_u64(seed(m.value_of("seed")));
+    let result = registration::register(&points, &params, &mut rng);
+    if let (Some(filename), Some(r)) = (m.value_of("draw"), result.as_ref()) {
+        let mut inliers = vec![None; b.len()];
+        for &i in r.inliers.iter() {
+            inliers[pairs[i].0] = matches[pairs[i].0];
+        }
+        let mut dst = to_rgba(&image_b);
+        draw_matches(&mut dst, &b, &inliers);
+        dst.save(filename)?;
     }
+    write_json(&json!({
+        "a": { "image": m.value_of("a"), "features": a.len() },
+        "b": { "image": m.value_of("b"), "features": b.len() },
+        "matches": points.len(),
+        "inliers": result.as_ref().map_or(0, |r| r.inliers.len()),
+        "homography": result.as_ref().map(|r| r.homography)
+    }), m)
+}
 
-    // open the source image as greyscale
-    let src_image =
-        image::open("res/im0.png").expect("failed to open image")
-            .into_luma8();
+// write a feature file for each image in a folder, skipping those already up to date
+fn extract(m:&ArgMatches) -> Result<()> {
+    let dir = Path::new(m.value_of("dir").unwrap());
+    let extensions:Vec<String> = m.values_of("ext").map_or(Vec::new(), |v| v.map(String::from).collect());
+    let config = config_from_args(m, Config::default())?;
+    let options = batch::BatchOptions {
+        format: batch::OutputFormat::parse(m.value_of("format").unwrap())?,
+        resize: dataset::ResizePolicy::parse(m.value_of("resize").unwrap())?,
+        threads: parse_arg(m, "threads")?.unwrap_or(0),
+        force: m.is_present("force")
+    };
+    let start = Instant::now();
+    let num = AtomicUsize::new(0);
+    let report = batch::extract_dir(dir, m.is_present("recursive"), &extensions,
+                                    Path::new(m.value_of("output").unwrap()), &config, &options, |item| {
+        let n = num.fetch_add(1, Ordering::SeqCst) + 1;
+        let outcome = match &item.outcome {
+            batch::Outcome::Written(features) => format!("{} features", features),
+            batch::Outcome::UpToDate => "up to date".to_string(),
+            batch::Outcome::Failed(e) => format!("failed: {}", e)
+        };
+        eprintln!("[{}] {} {} ({:.0}s elapsed)", n, item.input.display(), outcome, start.elapsed().as_secs_f32());
+    })?;
+    let failures:Vec<serde_json::Value> = report.failures()
+        .map(|item| json!({
+            "image": item.input,
+            "error": match &item.outcome { batch::Outcome::Failed(e) => e.to_string(), _ => String::new() }
+        }))
+        .collect();
+    write_json(&json!({
+        "images": report.items.len(),
+        "written": report.written(),
+        "up_to_date": report.up_to_date(),
+        "failed": failures
+    }), m)
+}
 
-    // resize to ~ 640x480
-    let (mut w, mut h) = src_image.dimensions();
-    h = h * 640 / w;
-    w = 640;
-    let src_image = imageops::resize(&src_image,
-        w, h, imageops::FilterType::CatmullRom);
-    println!("image {}x{}", w, h);
+// the name COLMAP gives an image, relative to the folder it was found in
+fn colmap_name(path:&Path, dirs:&[&str]) -> String {
+    dirs.iter()
+        .find_map(|d| path.strip_prefix(d).ok())
+        .unwrap_or(path)
+        .to_string_lossy()
+        .replace('\\', "/")
+}
 
-    // make a synthetically rotated copy
-    let theta = std::f32::consts::PI / 3.0;
-    let im_r = geometric_transformations::rotate_about_center(&src_image,
-                            theta,
-                            geometric_transformations::Interpolation::Nearest,
-                            Luma([0]));
+// keypoint files and verified matches for COLMAP to import, with keypoints at the
+// resolution of the original images whatever size they were detected at
+fn export_colmap(m:&ArgMatches) -> Result<()> {
+    let images = dataset_from_args(m)?;
+    let resize = dataset::ResizePolicy::parse(m.value_of("resize").unwrap())?;
+    let limit = parse_arg(m, "limit")?;
+    let config = config_from_args(m, Config::default())?;
+    let output = Path::new(m.value_of("output").unwrap());
+    fs::create_dir_all(output)?;
+    let dirs:Vec<&str> = m.values_of("inputs").map_or(Vec::new(), |v| v.collect());
+    let mut features = Vec::<(String, Vec<Corner>)>::new();
+    let num = for_each_image(&images, resize, limit, &mut |path, image| {
+        let corners = find_multiscale_features(image, &config)?;
+        let (w, h) = image::image_dimensions(path)?;
+        let scale = (w as f32 / image.width() as f32, h as f32 / image.height() as f32);
+        let name = colmap_name(path, &dirs);
+        colmap::save_keypoints(&output.join(format!("{}.txt", name)), &corners, scale)?;
+        features.push((name, corners));
+        Ok(())
+    });
+    if num == 0 {
+        return Err("no images could be read".into());
+    }
 
-    println!("finding features and matches in a copy rotated by {} degrees",
-             theta * 180.0 / std::f32::consts::PI);
+    let mut params = registration::RansacParams::default();
+    if let Some(v) = parse_arg(m, "iterations")? { params.iterations = v; }
+    if let Some(v) = parse_arg(m, "threshold")? { params.threshold = v; }
+    let mut rng = ChaCha8Rng::seed_from_u64(seed(m.value_of("seed")));
+    let image_pairs = colmap::image_pairs(features.len(), parse_arg(m, "window")?);
+    let mut pairs = Vec::new();
+    for (n, &(i, j)) in image_pairs.iter().enumerate() {
+        let matches = colmap::verified_matches(&features[i].1, &features[j].1, &config, &params, &mut rng)?;
+        eprintln!("[{}/{}] {} {}: {} matches", n + 1, image_pairs.len(),
+                  features[i].0, features[j].0, matches.len());
+        pairs.push(colmap::ImagePair {
+            a: features[i].0.clone(),
+            b: features[j].0.clone(),
+            matches: matches
+        });
+    }
+    colmap::save_matches(&output.join("matches.txt"), &pairs)?;
+    write_json(&json!({
+        "images": features.len(),
+        "pairs": pairs.iter().filter(|p| !p.matches.is_empty()).count(),
+        "matches": pairs.iter().map(|p| p.matches.len()).sum::<usize>()
+    }), m)
+}
 
-    // find ORB corners in both and matches between the pair
-    let mut config = Config::default();
-    config.lsh_k_l = (0, 1);
-    config.lsh_max_distance = 128;
-    
-    let corners = find_multiscale_features(&src_image, &config);
+// match features between an image and a rotated copy, where the true match is known
+fn eval(m:&ArgMatches) -> Result<()> {
+    let image = open_image(m, "image")?;
+    // consider every match so the best distance threshold can be found
+    let mut exhaustive = Config::default();
+    exhaustive.lsh_k_l = (0, 1);
+    exhaustive.lsh_max_distance = 128;
+    let mut config = config_from_args(m, exhaustive)?;
+    let degrees:f32 = parse_arg(m, "angle")?.unwrap_or(60.0);
+    let theta = degrees.to_radians();
 
     #[cfg(feature = "perf")]
     for _ in 0..100 {
-        let corners_r = find_multiscale_features(&im_r, &config);
-        let _matches = find_matches(&corners, &corners_r, &config);
+        evaluate_rotation(&image, theta, &config)?;
+    }
+
+    let mut results = Vec::<serde_json::Value>::new();
+    results.push(json!({
+        "name": if config.upright { "upright" } else { "oriented" },
+        "angle": degrees,
+        "stats": evaluate_rotation(&image, theta, &config)?
+    }));
+    if m.is_present("compare") {
+        // upright mode gives up rotation invariance so should only win on small rotations
+        let upright = config.upright;
+        let mut done = vec![(degrees, upright)];
+        for &d in [degrees, 5.0].iter() {
+            for &u in [false, true].iter() {
+                if done.contains(&(d, u)) {
+                    continue;
+                }
+                done.push((d, u));
+                config.upright = u;
+                results.push(json!({
+                    "name": if u { "upright" } else { "oriented" },
+                    "angle": d,
+                    "stats": evaluate_rotation(&image, d.to_radians(), &config)?
+                }));
+            }
+        }
+        config.upright = upright;
+
+        // compare against untrained sampling patterns
+        for &pattern in [rbrief::Pattern::Uniform, rbrief::Pattern::Gaussian,
+                         rbrief::Pattern::CentredGaussian, rbrief::Pattern::PolarGrid,
+                         rbrief::Pattern::CentredPolarGrid].iter() {
+            config.rbrief_test_set = rbrief::RBrief::from_test_set(
+                rbrief::TestSet::from_pattern(pattern, 0));
+            results.push(json!({
+                "name": format!("untrained {:?}", pattern),
+                "angle": degrees,
+                "stats": evaluate_rotation(&image, theta, &config)?
+            }));
+        }
+    }
+    write_json(&json!({
+        "image": m.value_of("image"),
+        "results": results
+    }), m)
+}
+
+// draw an image's features, or its matches with another image
+fn draw(m:&ArgMatches) -> Result<()> {
+    let image = open_image(m, "image")?;
+    let config = config_from_args(m, Config::default())?;
+    let corners = find_multiscale_features(&image, &config)?;
+    let mut dst = to_rgba(&image);
+    if m.is_present("matches") {
+        let other = open_image(m, "matches")?;
+        let other_corners = find_multiscale_features(&other, &config)?;
+        let matches = find_matches(&other_corners, &corners, &config)?;
+        draw_matches(&mut dst, &corners, &matches);
+    } else {
+        draw_features(&mut dst, &corners);
+    }
+    dst.save(m.value_of("output").unwrap())?;
+    Ok(())
+}
+
+// call f with each image of the dataset, up to limit, reporting progress and
+// returning how many were used. Images that can't be read or used are skipped.
+fn for_each_image(images:&dataset::Dataset, resize:dataset::ResizePolicy, limit:Option<usize>,
+                  f:&mut dyn FnMut(&Path, &GrayImage) -> image_processing::Result<()>) -> usize {
+    let total = limit.map_or(images.len(), |l| l.min(images.len()));
+    eprintln!("using {} of {} images", total, images.len());
+    let start = Instant::now();
+    let mut num = 0;
+    for path in images.paths.iter() {
+        if num == total {
+            break;
+        }
+        match dataset::load_image(path, resize).and_then(|image| f(path, &image).map(|_| image)) {
+            Ok(image) => {
+                num += 1;
+                let elapsed = start.elapsed().as_secs_f32();
+                let remaining = elapsed / num as f32 * (total - num) as f32;
+                eprintln!("[{}/{}] {} {}x{} ({:.0}s elapsed, ~{:.0}s remaining)",
+                          num, total, path.display(), image.width(), image.height(),
+                          elapsed, remaining);
+            },
+            Err(e) => eprintln!("skipping {}: {}", path.display(), e)
+        }
+    }
+    if num == 0 {
+        eprintln!("didn't find any images");
+    }
+    num
+}
+
+fn train(m:&ArgMatches) -> Result<()> {
+    // accumulate a bit array for every pair in 31x31 rect
+    // for each image
+    //   find features
+    //   for every feature
+    //     make integral image around point
+    //     for every pair in 31x31
+    //       rbrief test
+    //       push result into 1 bit of u64 array
+    // then perform greedy algorithm described in paper where
+    // mean = popcount(t) / num_images
+    // correlation = sum_over_R(popcount(Ri ^ t))
+    let mut images = dataset_from_args(m)?;
+    let config = config_from_args(m, Config::default())?;
+    let resize = dataset::ResizePolicy::parse(m.value_of("resize").unwrap())?;
+    let limit = parse_arg(m, "limit")?;
+    let output = m.value_of("output").unwrap();
+    let checkpoint:Option<usize> = parse_arg(m, "checkpoint")?;
+    let selection = selection(m.value_of("selection").unwrap());
+    let seed = seed(m.value_of("seed"));
+    let supervised = m.is_present("supervised");
+    if supervised && (checkpoint.is_some() || m.is_present("resume")) {
+        return Err("checkpoints are only supported for unsupervised training".into());
+    }
+    if let Some(dir) = Path::new(output).parent() {
+        fs::create_dir_all(dir)?;
     }
 
-    let corners_r = find_multiscale_features(&im_r, &config);
-    let matches = find_matches(&corners, &corners_r, &config);
- 
-    match_stats(&corners_r, &matches, (w, h, theta));
+    let kernel = config.rbrief_test_set.kernel();
+    let mut manifest = TrainingManifest::new(&config, kernel, seed);
+    manifest.supervised = supervised;
+    manifest.resize = resize;
 
-    let mut dst = src_image.expand_palette(&palette, None);
-    draw_features(&mut dst, &corners);
-    dst.save("features.png").expect("couldn't save");
-    
-    config.lsh_max_distance = 15;
-    let matches = find_matches(&corners, &corners_r, &config);
+    let (set, report) = if supervised {
+        // each image is paired with a randomly warped copy and tests that
+        // change between the two are avoided
+        eprintln!("training rBrief descriptor test set on warped image pairs");
+        let mut trainer = rbrief::SupervisedTrainer::with_kernel(kernel);
+        let range = WarpRange::default();
+        let mut rng = ChaCha8Rng::seed_from_u64(seed);
+        let num = for_each_image(&images, resize, limit, &mut |path, image| {
+            add_image_to_supervised_trainer(&mut trainer, image, &config, &range, &mut rng)?;
+            manifest.images.push(path.display().to_string());
+            Ok(())
+        });
+        if num == 0 {
+            return Err("no images could be read".into());
+        }
+        eprintln!("{} matching pairs", trainer.samples());
+        manifest.samples = trainer.samples();
+        trainer.make_test_set_with(selection.as_ref(), seed)?
+    } else {
+        eprintln!("training rBrief descriptor test set");
+        let mut trainer = match m.value_of("resume") {
+            Some(filename) => {
+                let trainer = rbrief::Trainer::load(filename)?;
+                if trainer.kernel() != kernel {
+                    return Err(format!("{} was trained with kernel {:?}", filename, trainer.kernel()).into());
+                }
+                // the images already accumulated are listed in the checkpoint's manifest
+                let resumed = TrainingManifest::load(&trainer_manifest_path(filename))?;
+                manifest.check_compatible(&resumed)?;
+                if resumed.samples != trainer.samples() {
+                    return Err(format!("{} has {} keypoints but its manifest lists {}", filename,
+                                       trainer.samples(), resumed.samples).into());
+                }
+                let done:HashSet<&String> = resumed.images.iter().collect();
+                images.paths.retain(|p| !done.contains(&p.display().to_string()));
+                manifest.images = resumed.images;
+                eprintln!("resuming from {} keypoints in {} images", trainer.samples(), manifest.images.len());
+                trainer
+            },
+            None => rbrief::Trainer::with_kernel(kernel)
+        };
+        let trainer_path = output_path(output, "trainer.bin");
+        let manifest_path = trainer_manifest_path(&trainer_path);
+        let mut since_checkpoint = 0;
+        let mut saved = Ok(());
+        let num = for_each_image(&images, resize, limit, &mut |path, image| {
+            add_image_to_trainer(&mut trainer, image, &config)?;
+            manifest.images.push(path.display().to_string());
+            since_checkpoint += 1;
+            if checkpoint == Some(since_checkpoint) && saved.is_ok() {
+                eprintln!("saving checkpoint {}", trainer_path);
+                manifest.samples = trainer.samples();
+                saved = trainer.save(&trainer_path).and_then(|_| manifest.save(&manifest_path));
+                since_checkpoint = 0;
+            }
+            Ok(())
+        });
+        saved?;
+        if num == 0 && trainer.samples() == 0 {
+            return Err("no images could be read".into());
+        }
+        // keep the scores so this run can be resumed or merged with others
+        trainer.save(&trainer_path)?;
+        manifest.samples = trainer.samples();
+        trainer.make_test_set_with(selection.as_ref(), seed)?
+    };
+    manifest.selection = report.selection.clone();
+    save_test_set(set, report, &manifest, output, m)
+}
 
-    let mut dst = im_r.expand_palette(&palette, None);
-    draw_matches(&mut dst, &corners_r, &matches);
-    dst.save("rotate_matches.png").expect("couldn't save");
+fn save_test_set(set:rbrief::TestSet, report:rbrief::TrainingReport, manifest:&TrainingManifest,
+                 output:&str, m:&ArgMatches) -> Result<()> {
+    set.save(output)?;
+    set.save_binary(&output_path(output, "bin"))?;
+    report.save(&output_path(output, "report.json"))?;
+    manifest.save(&output_path(output, "manifest.json"))?;
+    eprintln!("saved {}", output);
+    write_json(&json!({
+        "output": output,
+        "selection": report.selection,
+        "samples": report.samples,
+        "thresholds": report.thresholds,
+        "selected": report.selected,
+        "random": report.random
+    }), m)
+}
 
+fn merge_trainers(m:&ArgMatches) -> Result<()> {
+    let filenames:Vec<&str> = m.values_of("trainers").unwrap().collect();
+    let output = m.value_of("output").unwrap();
+    eprintln!("merging {} rBrief trainers", filenames.len());
+    let mut trainer = rbrief::Trainer::load(filenames[0])?;
+    let mut manifest = TrainingManifest::load(&trainer_manifest_path(filenames[0]))?;
+    for filename in filenames[1..].iter() {
+        trainer.merge(&rbrief::Trainer::load(filename)?)?;
+        manifest.merge(&TrainingManifest::load(&trainer_manifest_path(filename))?)?;
+    }
+    eprintln!("{} keypoints in total", trainer.samples());
+    trainer.save(&output_path(output, "trainer.bin"))?;
+    let (set, report) = trainer.make_test_set_with(&rbrief::GreedyThreshold::default(), manifest.seed)?;
+    manifest.selection = report.selection.clone();
+    save_test_set(set, report, &manifest, output, m)
+}
+
+// the manifest saved by the run that wrote a trainer, e.g. out.manifest.json for out.trainer.bin
+fn trainer_manifest_path(trainer:&str) -> String {
+    match trainer.strip_suffix(".trainer.bin") {
+        Some(output) => format!("{}.manifest.json", output),
+        None => output_path(trainer, "manifest.json")
+    }
+}
+
+// measure how the configured test set behaves on a set of images, and save
+// it pruned to the bits that are stable and decorrelated, best first
+fn diagnose_test_set(m:&ArgMatches) -> Result<()> {
+    let images = dataset_from_args(m)?;
+    let config = config_from_args(m, Config::default())?;
+    let resize = dataset::ResizePolicy::parse(m.value_of("resize").unwrap())?;
+    let max_correlation = parse_arg(m, "max-correlation")?.unwrap_or(0.3);
+    let max_flip_rate = parse_arg(m, "max-flip-rate")?.unwrap_or(0.25);
+    let output = m.value_of("output").unwrap();
+    let range = WarpRange::default();
+    let mut rng = ChaCha8Rng::seed_from_u64(0);
+    let bits = config.rbrief_test_set.test_set(0.0).set.len();
+    let mut stats = diagnostics::BitStatistics::new(bits);
+    let num = for_each_image(&images, resize, parse_arg(m, "limit")?, &mut |_path, image| {
+        add_warped_image_to_diagnostics(&mut stats, image, &config, &range, &mut rng)
+    });
+    if num == 0 {
+        return Err("no images could be read".into());
+    }
+    let d = stats.diagnostics();
+    d.save(&output_path(output, "diagnostics.json"))?;
+    let kept = d.prune(max_correlation, max_flip_rate);
+    config.rbrief_test_set.test_set(0.0).subset(&kept).save(output)?;
+    write_json(&json!({
+        "output": output,
+        "samples": d.samples,
+        "matched": d.matched,
+        "mean_entropy": d.entropy.iter().sum::<f32>() / bits as f32,
+        "bits": bits,
+        "kept": kept
+    }), m)
+}
+
+// write out the config the options give, as a starting point for a config file
+fn write_config(m:&ArgMatches) -> Result<()> {
+    let config = config_from_args(m, Config::default())?;
+    match m.value_of("output") {
+        Some(filename) => config.save(filename)?,
+        None => print!("{}", config.to_toml()?)
+    }
+    Ok(())
+}
+
+// draw a test set, coloured by the statistics of a saved trainer
+fn draw_test_set(m:&ArgMatches) -> Result<()> {
+    let set = rbrief::TestSet::load(m.value_of("test-set").unwrap())?;
+    let trainer = match m.value_of("trainer") {
+        Some(f) => Some(rbrief::Trainer::load(f)?),
+        None => None
+    };
+    // correlation by default when there are statistics to colour by
+    let colouring = match (m.value_of("colouring"), trainer.is_some()) {
+        (Some("mean"), _) => visualise::Colouring::Mean,
+        (Some("correlation"), _) | (None, true) => visualise::Colouring::Correlation,
+        _ => visualise::Colouring::Plain
+    };
+    let colours = visualise::test_colours(&set, trainer.as_ref(), colouring)?;
+    visualise::save(&set, &colours, 8, m.value_of("output").unwrap())?;
+    Ok(())
+}
+
+fn main() {
+    let two_images = || vec![
+        Arg::with_name("a").required(true).help("the image matched against"),
+        Arg::with_name("b").required(true).help("the image whose features are matched"),
+        resize_arg("original"),
+        Arg::with_name("draw").long("draw").takes_value(true)
+            .help("draw the matches over b into this image")
+    ];
+    let matches = App::new("image_processing")
+        .about("ORB features with trainable rBRIEF descriptors")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(SubCommand::with_name("detect")
+            .about("find and describe the features of an image")
+            .arg(Arg::with_name("image").required(true))
+            .arg(resize_arg("original"))
+            .arg(Arg::with_name("draw").long("draw").takes_value(true)
+                 .help("draw the features into this image"))
+            .arg(Arg::with_name("binary").long("binary").takes_value(true)
+                 .help("also save the features in the binary format"))
+            .arg(Arg::with_name("npz").long("npz").takes_value(true)
+                 .help("also save the keypoints and descriptors as numpy arrays"))
+            .arg(Arg::with_name("opencv").long("opencv").takes_value(true)
+                 .help("also save the keypoints and descriptors as OpenCV FileStorage yaml"))
+            .args(&config_args())
+            .arg(json_arg()))
+        .subcommand(SubCommand::with_name("match")
+            .about("match the features of two images")
+            .args(&two_images())
+            .args(&config_args())
+            .arg(json_arg()))
+        .subcommand(SubCommand::with_name("register")
+            .about("find the homography taking image b to image a")
+            .args(&two_images())
+            .args(&config_args())
+            .arg(Arg::with_name("iterations").long("iterations").takes_value(true))
+            .arg(Arg::with_name("threshold").long("threshold").takes_value(true)
+                 .help("largest reprojection error of an inlier in pixels"))
+            .arg(Arg::with_name("seed").long("seed").takes_value(true))
+            .arg(json_arg()))
+        .subcommand(SubCommand::with_name("extract")
+            .about("write the features of each image in a folder to a file, in parallel with the parallel feature")
+            .arg(Arg::with_name("dir").required(true).help("folder of images"))
+            .arg(Arg::with_name("output").short("o").long("output").takes_value(true).required(true)
+                 .help("folder for the feature files, laid out as the images are"))
+            .arg(Arg::with_name("format").long("format").takes_value(true).default_value("binary")
+                 .possible_values(&["json", "binary", "npz", "opencv"]))
+            .arg(Arg::with_name("recursive").short("r").long("recursive")
+                 .help("include images in subfolders"))
+            .arg(Arg::with_name("ext").long("ext").takes_value(true).use_delimiter(true)
+                 .default_value("png,jpg,jpeg,bmp,tif,tiff,pgm,ppm")
+                 .help("image file extensions to include"))
+            .arg(resize_arg("original"))
+            .arg(Arg::with_name("threads").short("j").long("threads").takes_value(true)
+                 .help("the most images to process at once with the parallel feature, defaults to one per core"))
+            .arg(Arg::with_name("force").long("force")
+                 .help("rewrite feature files that are already up to date"))
+            .args(&config_args())
+            .arg(json_arg()))
+        .subcommand(SubCommand::with_name("colmap")
+            .about("write keypoints and verified matches of a folder of images for COLMAP to import")
+            .args(&dataset_args())
+            .args(&config_args())
+            .arg(Arg::with_name("output").short("o").long("output").takes_value(true).required(true)
+                 .help("folder for the keypoint files, <image name>.txt, and matches.txt"))
+            .arg(Arg::with_name("window").long("window").takes_value(true)
+                 .help("only match images at most this many apart in order, as for video"))
+            .arg(Arg::with_name("iterations").long("iterations").takes_value(true))
+            .arg(Arg::with_name("threshold").long("threshold").takes_value(true)
+                 .help("largest reprojection error of an inlier in pixels"))
+            .arg(Arg::with_name("seed").long("seed").takes_value(true))
+            .arg(json_arg()))
+        .subcommand(SubCommand::with_name("train")
+            .about("train an rBRIEF test set on a set of images")
+            .args(&dataset_args())
+            .args(&config_args())
+            .arg(Arg::with_name("output").short("o").long("output").takes_value(true)
+                 .default_value("trained_test_set.json"))
+            .arg(Arg::with_name("selection").long("selection").takes_value(true)
+                 .possible_values(&["greedy", "incremental", "minmax"]).default_value("greedy"))
+            .arg(Arg::with_name("seed").long("seed").takes_value(true))
+            .arg(Arg::with_name("supervised").long("supervised")
+                 .help("train on pairs of each image and a randomly warped copy"))
+            .arg(Arg::with_name("checkpoint").long("checkpoint").takes_value(true)
+                 .help("save the trainer every this many images"))
+            .arg(Arg::with_name("resume").long("resume").takes_value(true)
+                 .help("continue from a saved trainer, skipping the images listed in the manifest beside it"))
+            .arg(json_arg()))
+        .subcommand(SubCommand::with_name("merge")
+            .about("combine saved trainers and make a test set from them")
+            .arg(Arg::with_name("trainers").required(true).multiple(true)
+                 .help("trainer files, each saved beside the manifest of its run"))
+            .arg(Arg::with_name("output").short("o").long("output").takes_value(true)
+                 .default_value("trained_test_set.json"))
+            .arg(json_arg()))
+        .subcommand(SubCommand::with_name("eval")
+            .about("measure matching against a rotated copy of an image")
+            .arg(Arg::with_name("image").required(true))
+            .arg(resize_arg("width:640"))
+            .arg(Arg::with_name("angle").long("angle").takes_value(true)
+                 .help("rotation in degrees, 60 by default"))
+            .arg(Arg::with_name("compare").long("compare")
+                 .help("also evaluate upright descriptors and untrained test sets"))
+            .args(&config_args())
+            .arg(json_arg()))
+        .subcommand(SubCommand::with_name("draw")
+            .about("draw the features of an image, or its matches with another")
+            .arg(Arg::with_name("image").required(true))
+            .arg(Arg::with_name("output").required(true))
+            .arg(Arg::with_name("matches").long("matches").takes_value(true)
+                 .help("draw matches with the features of this image"))
+            .arg(resize_arg("original"))
+            .args(&config_args()))
+        .subcommand(SubCommand::with_name("diagnose")
+            .about("measure each bit of the test set on a set of images")
+            .args(&dataset_args())
+            .args(&config_args())
+            .arg(Arg::with_name("output").short("o").long("output").takes_value(true)
+                 .default_value("pruned_test_set.json"))
+            .arg(Arg::with_name("max-correlation").long("max-correlation").takes_value(true))
+            .arg(Arg::with_name("max-flip-rate").long("max-flip-rate").takes_value(true))
+            .arg(json_arg()))
+        .subcommand(SubCommand::with_name("draw-test-set")
+            .about("draw a test set as an image or SVG")
+            .arg(Arg::with_name("test-set").required(true))
+            .arg(Arg::with_name("output").required(true))
+            .arg(Arg::with_name("trainer").long("trainer").takes_value(true)
+                 .help("a saved trainer whose statistics colour the tests"))
+            .arg(Arg::with_name("colouring").long("colouring").takes_value(true)
+                 .possible_values(&["plain", "mean", "correlation"])
+                 .help("mean and correlation need a trainer [default: correlation with a trainer, else plain]")))
+        .subcommand(SubCommand::with_name("config")
+            .about("write the resolved config as toml, or as json if the output isn't .toml")
+            .args(&config_args())
+            .arg(Arg::with_name("output").short("o").long("output").takes_value(true)))
+        .get_matches();
+
+    let result = match matches.subcommand() {
+        ("detect", Some(m)) => detect(m),
+        ("match", Some(m)) => match_images(m),
+        ("register", Some(m)) => register(m),
+        ("extract", Some(m)) => extract(m),
+        ("colmap", Some(m)) => export_colmap(m),
+        ("train", Some(m)) => train(m),
+        ("merge", Some(m)) => merge_trainers(m),
+        ("eval", Some(m)) => eval(m),
+        ("draw", Some(m)) => draw(m),
+        ("diagnose", Some(m)) => diagnose_test_set(m),
+        ("draw-test-set", Some(m)) => draw_test_set(m),
+        ("config", Some(m)) => write_config(m),
+        _ => Ok(())
+    };
+    if let Err(e) = result {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
 }
@@ -0,0 +1,267 @@
+use std::fs;
+use std::io::{Read, Seek, Write};
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+use zip::write::FileOptions;
+use crate::error::{Error, Result};
+use crate::features::Keypoint;
+
+// NumPy's .npy format: a magic string, version, a python dict literal header
+// padded to a multiple of 64 bytes, then the array data in C order
+const NPY_MAGIC:&[u8; 6] = b"\x93NUMPY";
+
+// the columns of the keypoints array
+pub const KEYPOINT_COLUMNS:[&str; 5] = ["x", "y", "level", "angle", "score"];
+// the bytes of each descriptor row, bit i of a descriptor being bit i % 8 of byte i / 8
+pub const DESCRIPTOR_BYTES:usize = 16;
+
+// an array read from a .npy file, its data still as the file's bytes
+#[derive(Clone, Debug, PartialEq)]
+pub struct NpyArray {
+    // the numpy type string, such as "<f4" or "|u1"
+    pub descr: String,
+    pub shape: Vec<usize>,
+    pub data: Vec<u8>
+}
+
+impl NpyArray {
+    fn element_size(&self) -> Result<usize> {
+        self.descr.get(2..)
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(|| Error::Format(format!("unsupported npy type {}", self.descr)))
+    }
+
+    pub fn to_f32(&self) -> Result<Vec<f32>> {
+        if self.descr != "<f4" {
+            return Err(Error::Format(format!("expected float32 npy array, not {}", self.descr)));
+        }
+        Ok(self.data.chunks(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect())
+    }
+
+    pub fn to_u8(&self) -> Result<&[u8]> {
+        if self.descr != "|u1" && self.descr != "<u1" {
+            return Err(Error::Format(format!("expected uint8 npy array, not {}", self.descr)));
+        }
+        Ok(&self.data)
+    }
+}
+
+pub fn write_npy<W:Write>(w:&mut W, descr:&str, shape:&[usize], data:&[u8]) -> Result<()> {
+    let shape = match shape.len() {
+        1 => format!("({},)", shape[0]),
+        _ => format!("({})", shape.iter().map(|s| s.to_string()).collect::<Vec<_>>().join(", "))
+    };
+    let mut header = format!("{{'descr': '{}', 'fortran_order': False, 'shape': {}, }}", descr, shape);
+    // pad so the data starts on a 64 byte boundary, ending the header with a newline
+    let unpadded = NPY_MAGIC.len() + 4 + header.len() + 1;
+    header.push_str(&" ".repeat((64 - unpadded % 64) % 64));
+    header.push('\n');
+    w.write_all(NPY_MAGIC)?;
+    w.write_all(&[1u8, 0u8])?;
+    w.write_all(&(header.len() as u16).to_le_bytes())?;
+    w.write_all(header.as_bytes())?;
+    w.write_all(data)?;
+    Ok(())
+}
+
+// the value of key in a header dict such as {'descr': '<f4', 'shape': (3, 5), }
+fn header_value<'a>(header:&'a str, key:&str) -> Result<&'a str> {
+    let missing = || Error::Format(format!("npy header has no {}", key));
+    let start = header.find(&format!("'{}':", key)).ok_or_else(missing)? + key.len() + 3;
+    let rest = header[start..].trim_start();
+    let end = if rest.starts_with('(') {
+        rest.find(')').map(|i| i + 1)
+    } else {
+        rest.find(|c| c == ',' || c == '}')
+    };
+    Ok(rest[..end.ok_or_else(missing)?].trim())
+}
+
+pub fn read_npy(bytes:&[u8]) -> Result<NpyArray> {
+    if bytes.len() < 10 || &bytes[..6] != NPY_MAGIC {
+        return Err(Error::Format("not an npy file".to_string()));
+    }
+    let (header_len, offset) = match bytes[6] {
+        1 => (u16::from_le_bytes([bytes[8], bytes[9]]) as usize, 10),
+        2 | 3 if bytes.len() >= 12 =>
+            (u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]) as usize, 12),
+        v => return Err(Error::Format(format!("unsupported npy version {}", v)))
+    };
+    let header = bytes.get(offset..offset + header_len)
+        .ok_or_else(|| Error::Format("truncated npy header".to_string()))?;
+    let header = std::str::from_utf8(header)?;
+    if header_value(header, "fortran_order")? != "False" {
+        return Err(Error::Format("fortran ordered npy arrays aren't supported".to_string()));
+    }
+    let descr = header_value(header, "descr")?.trim_matches(|c| c == '\'' || c == '"').to_string();
+    let shape = header_value(header, "shape")?
+        .trim_matches(|c| c == '(' || c == ')')
+        .split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(|s| s.parse::<usize>())
+        .collect::<std::result::Result<Vec<usize>, _>>()?;
+    let mut array = NpyArray {
+        descr: descr,
+        shape: shape,
+        data: Vec::new()
+    };
+    let element_size = array.element_size()?;
+    let len = array.shape.iter()
+        .try_fold(element_size, |n, &d| n.checked_mul(d))
+        .ok_or_else(|| Error::Format(format!("npy array of shape {:?} is too large", array.shape)))?;
+    let data = &bytes[offset + header_len..];
+    if data.len() != len {
+        return Err(Error::Format(format!("npy array of shape {:?} has {} bytes of data",
+                                         array.shape, data.len())));
+    }
+    array.data = data.to_vec();
+    Ok(array)
+}
+
+// an N x 5 float32 array with the columns of KEYPOINT_COLUMNS
+pub fn keypoints_npy(keypoints:&[Keypoint]) -> Result<Vec<u8>> {
+    let data:Vec<u8> = keypoints.iter()
+        .flat_map(|k| vec![k.x, k.y, k.level as f32, k.angle, k.score])
+        .flat_map(|v| v.to_le_bytes().to_vec())
+        .collect();
+    let mut bytes = Vec::new();
+    write_npy(&mut bytes, "<f4", &[keypoints.len(), KEYPOINT_COLUMNS.len()], &data)?;
+    Ok(bytes)
+}
+
+// an N x 16 uint8 array, with zeros for keypoints that weren't described, so
+// described_npy tells those from a descriptor of all zeros
+pub fn descriptors_npy(keypoints:&[Keypoint]) -> Result<Vec<u8>> {
+    let data:Vec<u8> = keypoints.iter()
+        .flat_map(|k| k.descriptor.unwrap_or(0).to_le_bytes().to_vec())
+        .collect();
+    let mut bytes = Vec::new();
+    write_npy(&mut bytes, "|u1", &[keypoints.len(), DESCRIPTOR_BYTES], &data)?;
+    Ok(bytes)
+}
+
+// an N uint8 array of 1 for each keypoint with a descriptor and 0 for those without
+pub fn described_npy(keypoints:&[Keypoint]) -> Result<Vec<u8>> {
+    let data:Vec<u8> = keypoints.iter().map(|k| k.descriptor.is_some() as u8).collect();
+    let mut bytes = Vec::new();
+    write_npy(&mut bytes, "|u1", &[keypoints.len()], &data)?;
+    Ok(bytes)
+}
+
+// keypoints.npy, descriptors.npy and described.npy bundled as numpy.load reads them
+pub fn write_npz<W:Write + Seek>(w:W, keypoints:&[Keypoint]) -> Result<()> {
+    let mut zip = ZipWriter::new(w);
+    let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+    zip.start_file("keypoints.npy", options)?;
+    zip.write_all(&keypoints_npy(keypoints)?)?;
+    zip.start_file("descriptors.npy", options)?;
+    zip.write_all(&descriptors_npy(keypoints)?)?;
+    zip.start_file("described.npy", options)?;
+    zip.write_all(&described_npy(keypoints)?)?;
+    zip.finish()?;
+    Ok(())
+}
+
+pub fn save_npz(filename:&str, keypoints:&[Keypoint]) -> Result<()> {
+    write_npz(fs::File::create(filename)?, keypoints)
+}
+
+fn read_npz_array<R:Read + Seek>(zip:&mut ZipArchive<R>, name:&str) -> Result<NpyArray> {
+    let mut bytes = Vec::new();
+    zip.by_name(name)?.read_to_end(&mut bytes)?;
+    read_npy(&bytes)
+}
+
+// keypoints from an npz written by write_npz or by numpy.savez with the same
+// arrays. Without described.npy every keypoint is taken to have a descriptor.
+pub fn read_npz<R:Read + Seek>(r:R) -> Result<Vec<Keypoint>> {
+    let mut zip = ZipArchive::new(r)?;
+    let keypoints = read_npz_array(&mut zip, "keypoints.npy")?;
+    let descriptors = read_npz_array(&mut zip, "descriptors.npy")?;
+    let n = keypoints.shape.first().cloned().unwrap_or(0);
+    if keypoints.shape != [n, KEYPOINT_COLUMNS.len()] || descriptors.shape != [n, DESCRIPTOR_BYTES] {
+        return Err(Error::Format(format!("expected {} x {} keypoints and {} x {} descriptors, not {:?} and {:?}",
+                                         n, KEYPOINT_COLUMNS.len(), n, DESCRIPTOR_BYTES,
+                                         keypoints.shape, descriptors.shape)));
+    }
+    let described = if zip.file_names().any(|name| name == "described.npy") {
+        let described = read_npz_array(&mut zip, "described.npy")?;
+        if described.shape != [n] {
+            return Err(Error::Format(format!("expected {} described flags, not {:?}", n, described.shape)));
+        }
+        described.to_u8()?.iter().map(|&d| d != 0).collect()
+    } else {
+        vec![true; n]
+    };
+    let values = keypoints.to_f32()?;
+    Ok(values.chunks(KEYPOINT_COLUMNS.len())
+        .zip(descriptors.to_u8()?.chunks(DESCRIPTOR_BYTES))
+        .zip(described.iter())
+        .map(|((k, d), &described)| {
+            let mut word = [0u8; DESCRIPTOR_BYTES];
+            word.copy_from_slice(d);
+            let descriptor = u128::from_le_bytes(word);
+            Keypoint {
+                x: k[0],
+                y: k[1],
+                level: k[2] as u32,
+                angle: k[3],
+                score: k[4],
+                orientation_confidence: 0.0,
+                descriptor: if described { Some(descriptor) } else { None }
+            }
+        })
+        .collect())
+}
+
+pub fn load_npz(filename:&str) -> Result<Vec<Keypoint>> {
+    read_npz(fs::File::open(filename)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_npz() {
+        let keypoints:Vec<Keypoint> = (0..3)
+            .map(|i| Keypoint {
+                x: i as f32 * 2.0,
+                y: 10.5,
+                level: i,
+                angle: -0.25,
+                score: 3.0,
+                orientation_confidence: 0.0,
+                descriptor: match i { 0 => Some(0), 1 => None, _ => Some(0x0102u128 << i) }
+            })
+            .collect();
+        let npy = keypoints_npy(&keypoints).unwrap();
+        // the data is 64 byte aligned as numpy writes it
+        assert_eq!((npy.len() - 3 * 5 * 4) % 64, 0);
+        assert!(std::str::from_utf8(&npy[10..npy.len() - 3 * 5 * 4]).unwrap()
+                .starts_with("{'descr': '<f4', 'fortran_order': False, 'shape': (3, 5), }"));
+        let array = read_npy(&npy).unwrap();
+        assert_eq!(array.shape, vec![3, 5]);
+        assert_eq!(&array.to_f32().unwrap()[5..10], &[2.0, 10.5, 1.0, -0.25, 3.0]);
+        let descriptors = read_npy(&descriptors_npy(&keypoints).unwrap()).unwrap();
+        assert_eq!(&descriptors.to_u8().unwrap()[32..34], &[0x08, 0x04]);
+        assert!(descriptors.to_f32().is_err());
+        assert!(read_npy(&npy[..npy.len() - 4]).is_err());
+        // a shape whose size overflows is rejected rather than wrapping
+        let mut huge = Vec::new();
+        write_npy(&mut huge, "<f4", &[usize::MAX / 2, 4], &[]).unwrap();
+        assert!(read_npy(&huge).is_err());
+
+        let mut npz = Cursor::new(Vec::new());
+        write_npz(&mut npz, &keypoints).unwrap();
+        npz.set_position(0);
+        assert_eq!(read_npz(npz).unwrap(), keypoints);
+
+        let mut one_d = Vec::new();
+        write_npy(&mut one_d, "<f4", &[2], &[0u8; 8]).unwrap();
+        assert_eq!(read_npy(&one_d).unwrap().shape, vec![2]);
+    }
+}
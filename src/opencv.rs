@@ -0,0 +1,258 @@
+use std::fs;
+use crate::error::{Error, Result};
+use crate::features::Keypoint;
+use crate::rbrief::HWIDTH;
+
+// OpenCV FileStorage YAML, as written by cv::FileStorage with a vector of
+// cv::KeyPoint under "keypoints" and a CV_8U matrix under "descriptors".
+// Keypoints are [x, y, size, angle, response, octave, class_id] with the angle
+// in degrees from 0 to 360. Descriptor rows hold bit i in bit i % 8 of byte i / 8,
+// as OpenCV's ORB does. to_yaml also writes a "described" sequence of 1 for each
+// keypoint with a descriptor and 0 for those without, which OpenCV ignores.
+
+const DESCRIPTOR_BYTES:usize = 16;
+
+// the width in base image pixels of the patch a keypoint is described from
+pub fn keypoint_size(level:u32) -> f32 {
+    ((2 * HWIDTH + 1) << level) as f32
+}
+
+fn degrees(angle:f32) -> f32 {
+    angle.to_degrees().rem_euclid(360.0)
+}
+
+// back to radians from -pi to pi as centroid gives them
+fn radians(degrees:f32) -> f32 {
+    let r = degrees.to_radians();
+    if r > std::f32::consts::PI { r - 2.0 * std::f32::consts::PI } else { r }
+}
+
+pub fn to_yaml(keypoints:&[Keypoint]) -> String {
+    let mut yaml = "%YAML:1.0\n---\n".to_string();
+    if keypoints.is_empty() {
+        yaml += "keypoints: []\n";
+    } else {
+        yaml += "keypoints:\n";
+        for k in keypoints.iter() {
+            yaml += &format!("   - [ {:?}, {:?}, {:?}, {:?}, {:?}, {}, -1 ]\n",
+                             k.x, k.y, keypoint_size(k.level), degrees(k.angle), k.score, k.level);
+        }
+    }
+    yaml += &format!("descriptors: !!opencv-matrix\n   rows: {}\n   cols: {}\n   dt: u\n   data: [",
+                     keypoints.len(), DESCRIPTOR_BYTES);
+    let rows:Vec<String> = keypoints.iter()
+        .map(|k| k.descriptor.unwrap_or(0).to_le_bytes().iter()
+             .map(|b| b.to_string())
+             .collect::<Vec<_>>()
+             .join(", "))
+        .collect();
+    yaml += &rows.join(",\n       ");
+    yaml += "]\n";
+    let described:Vec<&str> = keypoints.iter().map(|k| if k.descriptor.is_some() { "1" } else { "0" }).collect();
+    yaml += &format!("described: [ {} ]\n", described.join(", "));
+    yaml
+}
+
+pub fn save_yaml(filename:&str, keypoints:&[Keypoint]) -> Result<()> {
+    fs::write(filename, to_yaml(keypoints))?;
+    Ok(())
+}
+
+// the text of each top level node, by name
+fn top_level_nodes(yaml:&str) -> Vec<(&str, String)> {
+    let mut nodes = Vec::<(&str, String)>::new();
+    for line in yaml.lines() {
+        let line = without_comment(line);
+        if line.starts_with('%') || line.starts_with("---") || line.trim().is_empty() {
+            continue;
+        }
+        // a name ends at the first ": ", or a colon ending the line, so values such
+        // as paths and times can hold colons of their own
+        let end = line.trim_end();
+        let colon = line.find(": ").or_else(|| if end.ends_with(':') { Some(end.len() - 1) } else { None });
+        match (line.starts_with(char::is_whitespace), colon) {
+            (false, Some(i)) => nodes.push((line[..i].trim(), line[i + 1..].to_string())),
+            _ => if let Some(node) = nodes.last_mut() {
+                node.1.push('\n');
+                node.1.push_str(line);
+            }
+        }
+    }
+    nodes
+}
+
+// line up to a comment, which is a # starting a token outside quotes
+fn without_comment(line:&str) -> &str {
+    let mut quote = None;
+    let mut previous = ' ';
+    for (i, c) in line.char_indices() {
+        match quote {
+            Some(q) => if c == q { quote = None },
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == '#' && previous.is_whitespace() => return &line[..i],
+            None => {}
+        }
+        previous = c;
+    }
+    line
+}
+
+// the numbers in a sequence, nested or flat, ignoring block sequence dashes
+fn numbers(text:&str) -> Result<Vec<f32>> {
+    text.split(|c:char| c == '[' || c == ']' || c == ',' || c.is_whitespace())
+        .filter(|s| !s.is_empty() && *s != "-")
+        .map(|s| s.parse::<f32>().map_err(|_| Error::Format(format!("expected a number, not {}", s))))
+        .collect()
+}
+
+// the value of a field of a matrix node such as rows: 500
+fn matrix_field<'a>(text:&'a str, field:&str) -> Result<&'a str> {
+    let start = text.find(&format!("{}:", field))
+        .ok_or_else(|| Error::Format(format!("opencv matrix has no {}", field)))? + field.len() + 1;
+    let rest = &text[start..];
+    Ok(if field == "data" { rest } else { rest.lines().next().unwrap_or("").trim() })
+}
+
+// the descriptors of a CV_8U matrix of 16 byte rows, or with truncate the first
+// 16 bytes of longer rows such as OpenCV's 256 bit ORB
+fn descriptors(text:&str, truncate:bool) -> Result<Vec<u128>> {
+    let rows:usize = matrix_field(text, "rows")?.parse()?;
+    let cols:usize = matrix_field(text, "cols")?.parse()?;
+    if cols != DESCRIPTOR_BYTES && !(truncate && cols > DESCRIPTOR_BYTES) {
+        return Err(Error::Format(format!("expected descriptors of {} bytes, not {}", DESCRIPTOR_BYTES, cols)));
+    }
+    let dt = matrix_field(text, "dt")?;
+    if dt != "u" {
+        return Err(Error::Format(format!("expected CV_8U descriptors, not type {}", dt)));
+    }
+    let data = numbers(matrix_field(text, "data")?)?;
+    if data.len() != rows * cols {
+        return Err(Error::Format(format!("{} x {} matrix has {} values", rows, cols, data.len())));
+    }
+    Ok(data.chunks(cols.max(1))
+        .map(|row| {
+            let mut bytes = [0u8; DESCRIPTOR_BYTES];
+            for (b, v) in bytes.iter_mut().zip(row.iter()) {
+                *b = *v as u8;
+            }
+            u128::from_le_bytes(bytes)
+        })
+        .collect())
+}
+
+// keypoints, with descriptors if there are any, from yaml written by OpenCV or
+// to_yaml. Both OpenCV's nested and older flat keypoint sequences are read.
+pub fn from_yaml(yaml:&str) -> Result<Vec<Keypoint>> {
+    read_yaml(yaml, false)
+}
+
+// as from_yaml, keeping the first 128 bits of longer descriptors. These aren't
+// rBRIEF descriptors, so they can only be matched against each other.
+pub fn from_yaml_truncated(yaml:&str) -> Result<Vec<Keypoint>> {
+    read_yaml(yaml, true)
+}
+
+fn read_yaml(yaml:&str, truncate:bool) -> Result<Vec<Keypoint>> {
+    let nodes = top_level_nodes(yaml);
+    let node = |name:&str| nodes.iter().find(|n| n.0 == name).map(|n| n.1.as_str());
+    let values = numbers(node("keypoints")
+                         .ok_or_else(|| Error::Format("no keypoints in yaml".to_string()))?)?;
+    if values.len() % 7 != 0 {
+        return Err(Error::Format(format!("{} keypoint values isn't a multiple of 7", values.len())));
+    }
+    let n = values.len() / 7;
+    let descriptors = match node("descriptors") {
+        Some(text) => Some(descriptors(text, truncate)?),
+        None => None
+    };
+    if let Some(d) = descriptors.as_ref() {
+        if d.len() != n {
+            return Err(Error::Format(format!("{} descriptors for {} keypoints", d.len(), n)));
+        }
+    }
+    // without the flags every row of the matrix is a descriptor
+    let described = match node("described") {
+        Some(text) => numbers(text)?.iter().map(|&d| d != 0.0).collect(),
+        None => vec![true; n]
+    };
+    if described.len() != n {
+        return Err(Error::Format(format!("{} described flags for {} keypoints", described.len(), n)));
+    }
+    Ok(values.chunks(7).enumerate()
+        .map(|(i, k)| Keypoint {
+            x: k[0],
+            y: k[1],
+            level: k[5].max(0.0) as u32,
+            angle: radians(k[3]),
+            score: k[4],
+            orientation_confidence: 0.0,
+            descriptor: descriptors.as_ref().map(|d| d[i]).filter(|_| described[i])
+        })
+        .collect())
+}
+
+pub fn load_yaml(filename:&str) -> Result<Vec<Keypoint>> {
+    from_yaml(&fs::read_to_string(filename)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_opencv_yaml() {
+        let keypoints:Vec<Keypoint> = (0..3)
+            .map(|i| Keypoint {
+                x: 10.0 + i as f32,
+                y: 20.5,
+                level: i,
+                angle: -1.5 + i as f32,
+                score: 0.125,
+                orientation_confidence: 0.0,
+                descriptor: match i { 0 => Some(0), 1 => None, _ => Some(0xff01u128 << (i * 8)) }
+            })
+            .collect();
+        let yaml = to_yaml(&keypoints);
+        assert!(yaml.starts_with("%YAML:1.0\n---\nkeypoints:\n   - [ 10.0, 20.5, 31.0, "));
+        assert!(yaml.contains("   dt: u\n   data: [0, 0, 0,"));
+        assert!(yaml.ends_with("described: [ 1, 0, 1 ]\n"));
+        let read = from_yaml(&yaml).unwrap();
+        for (a, b) in read.iter().zip(keypoints.iter()) {
+            assert!((a.angle - b.angle).abs() < 1e-5);
+            assert_eq!((a.x, a.y, a.level, a.score, a.descriptor), (b.x, b.y, b.level, b.score, b.descriptor));
+        }
+        assert!(from_yaml(&to_yaml(&[])).unwrap().is_empty());
+
+        // other nodes and comments, with values holding colons and hashes
+        let annotated = yaml.replacen("---\n", "---\n# written by a tool: v1\n\
+                                       image: \"C:/data/run #2/a.png\" # the source\n\
+                                       provenance: 'trained #3: 10:30'\n\
+                                       url: http://example.com/a#b\n", 1);
+        let nodes = top_level_nodes(&annotated);
+        let node = |name:&str| nodes.iter().find(|n| n.0 == name).map(|n| n.1.trim().to_string());
+        assert_eq!(node("image"), Some("\"C:/data/run #2/a.png\"".to_string()));
+        assert_eq!(node("provenance"), Some("'trained #3: 10:30'".to_string()));
+        assert_eq!(node("url"), Some("http://example.com/a#b".to_string()));
+        let reread = from_yaml(&annotated).unwrap();
+        assert_eq!(reread.len(), read.len());
+        for (a, b) in reread.iter().zip(read.iter()) {
+            assert_eq!((a.x, a.y, a.level, a.angle, a.descriptor), (b.x, b.y, b.level, b.angle, b.descriptor));
+        }
+
+        // OpenCV 3's flat keypoints with 32 byte ORB descriptors
+        let opencv = "%YAML:1.0\nkeypoints: [ 1.5e+02, 46., 31., 270., 1.8e-04, 0, -1,\n    \
+                      12., 7., 37.2, 3.5e+01, 2.0e-04, 1, -1 ]\n\
+                      descriptors: !!opencv-matrix\n   rows: 2\n   cols: 32\n   dt: u\n   data: [ 3, 0, 0, 0, 0, 0, 0, 0,\n       \
+                      0, 0, 0, 0, 0, 0, 0, 0, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9,\n       \
+                      1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9 ]\n";
+        assert!(from_yaml(opencv).is_err());
+        let read = from_yaml_truncated(opencv).unwrap();
+        assert_eq!(read.len(), 2);
+        assert_eq!((read[0].x, read[0].level, read[0].descriptor), (150.0, 0, Some(3)));
+        assert!((read[0].angle + std::f32::consts::FRAC_PI_2).abs() < 1e-5);
+        assert_eq!((read[1].level, read[1].descriptor), (1, Some(1)));
+
+        assert!(from_yaml("%YAML:1.0\nkeypoints: [ 1., 2., 3. ]\n").is_err());
+        assert!(from_yaml_truncated(&opencv.replace("rows: 2", "rows: 3")).is_err());
+    }
+}
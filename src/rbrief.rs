@@ -1,14 +1,25 @@
-use rand::{Rng};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
 use rand::seq::SliceRandom;
 use rand::distributions::{Uniform};
 use image::{imageops, GrayImage, Luma};
-use imageproc::{integral_image};
+use imageproc::{filter, integral_image};
 use imageproc::definitions::Image;
 use ordered_float::OrderedFloat;
-use serde::{Serialize, Deserialize};
-use std::{error, fs};
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+use serde::{de, Serialize, Serializer, Deserialize, Deserializer};
+use crate::error::{Error, Result};
+use std::fs;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 
 type GrayIntegral = Image<Luma<u32>>;
+type GrayFloat = Image<Luma<f32>>;
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Point {
@@ -16,12 +27,67 @@ pub struct Point {
     pub y: i32
 }
 
-pub fn sample(image:&GrayIntegral, offset:&Point, p:&Point) -> u32 {
-    let l = (offset.x + p.x - 2) as u32;
-    let r = (offset.x + p.x + 2) as u32;
-    let t = (offset.y + p.y - 2) as u32;
-    let b = (offset.y + p.y + 2) as u32;
-    integral_image::sum_image_pixels(image, l, t, r, b)[0]
+// how the image is sampled around each test point
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Kernel {
+    // sum over a square box of the given odd width
+    Box(u32),
+    // a single pixel of the patch smoothed by a gaussian of the given sigma
+    Gaussian(f32)
+}
+
+impl Default for Kernel {
+    fn default() -> Kernel {
+        Kernel::Box(WINDOW as u32)
+    }
+}
+
+impl Kernel {
+    // parse "box:5" or "gaussian:1.5"
+    pub fn parse(s:&str) -> Result<Kernel> {
+        let mut parts = s.splitn(2, ':');
+        match (parts.next(), parts.next()) {
+            (Some("box"), Some(v)) => Ok(Kernel::Box(v.parse()?)),
+            (Some("gaussian"), Some(v)) => Ok(Kernel::Gaussian(v.parse()?)),
+            _ => Err(Error::Parse(format!("unknown kernel {}", s)))
+        }
+    }
+
+    // how far beyond a test point the kernel reads
+    pub fn margin(&self) -> u32 {
+        match self {
+            Kernel::Box(width) => width / 2,
+            Kernel::Gaussian(sigma) => (3.0 * sigma).ceil() as u32
+        }
+    }
+
+    // the radius of the patch needed to describe a corner at any angle
+    pub fn radius(&self) -> u32 {
+        (HWIDTH as f64 * std::f64::consts::SQRT_2) as u32 + self.margin()
+    }
+}
+
+// the area around a corner prepared for sampling with a kernel
+pub enum Patch {
+    Box(GrayIntegral, i32),
+    Gaussian(GrayFloat)
+}
+
+pub fn sample(patch:&Patch, offset:&Point, p:&Point) -> u32 {
+    match patch {
+        Patch::Box(integral, h) => {
+            let l = (offset.x + p.x - h) as u32;
+            let r = (offset.x + p.x + h) as u32;
+            let t = (offset.y + p.y - h) as u32;
+            let b = (offset.y + p.y + h) as u32;
+            integral_image::sum_image_pixels(integral, l, t, r, b)[0]
+        },
+        Patch::Gaussian(smoothed) => {
+            // keep some sub grey level precision for the comparison
+            let v = smoothed.get_pixel((offset.x + p.x) as u32, (offset.y + p.y) as u32)[0];
+            (v * 256.0).round() as u32
+        }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
@@ -75,52 +141,369 @@ impl PairPoint {
     }
 }
 
-pub fn test(image:&GrayIntegral, offset:&Point, p:&PairPoint) -> bool {
-    sample(image, offset, &p.0) > sample(image, offset, &p.1)
+pub fn test(patch:&Patch, offset:&Point, p:&PairPoint) -> bool {
+    sample(patch, offset, &p.0) > sample(patch, offset, &p.1)
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct TestSet {
-    pub set: Vec<PairPoint>
+    pub set: Vec<PairPoint>,
+    #[serde(default)]
+    pub kernel: Kernel,
+    // where the set came from, e.g. the pattern or training run
+    #[serde(default)]
+    pub provenance: String
+}
+
+pub const TEST_SET_VERSION:u32 = 1;
+const TEST_SET_MAGIC:&[u8; 4] = b"RBTS";
+
+// the trained set shipped with the library
+const TRAINED_TEST_SET:&str = include_str!("../res/trained_test_set.json");
+
+// the header written ahead of the pairs so a file can be checked before use
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TestSetHeader {
+    pub version: u32,
+    pub descriptor_bits: u32,
+    pub max_offset: i32,
+    pub kernel: Kernel,
+    pub provenance: String
+}
+
+#[derive(Serialize, Deserialize)]
+struct VersionedTestSet {
+    header: TestSetHeader,
+    set: Vec<PairPoint>
 }
 
-type Result<T> = std::result::Result<T, Box<dyn error::Error>>;
+// test sets saved before the format was versioned are either a bare list of
+// pairs or, once the kernel was configurable, a set and kernel
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TestSetFile {
+    Versioned(VersionedTestSet),
+    Pairs(Vec<PairPoint>),
+    TestSet(TestSet)
+}
+
+// the test point distributions compared in
+// "BRIEF: Binary Robust Independent Elementary Features", Calonder et al. 2010
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Pattern {
+    // G I: both points uniform over the patch
+    Uniform,
+    // G II: both points from an isotropic gaussian with sigma = S / 5
+    Gaussian,
+    // G III: the first point as G II, the second from a gaussian centred on the
+    // first with sigma = S / 10
+    CentredGaussian,
+    // G IV: both points from a coarse polar grid
+    PolarGrid,
+    // G V: the first point at the centre, the second on a polar grid
+    CentredPolarGrid
+}
+
+fn normal<R:Rng>(rng:&mut R, mean:f32, sigma:f32) -> i32 {
+    // Box-Muller
+    let u1:f32 = rng.gen_range(std::f32::EPSILON, 1.0);
+    let u2:f32 = rng.gen();
+    let n = (-2.0 * u1.ln()).sqrt() * (2.0 * std::f32::consts::PI * u2).cos();
+    num::clamp((mean + sigma * n).round() as i32, -MAX, MAX)
+}
+
+// the centre and rings of points out to MAX, without duplicates
+fn polar_grid(rings:i32, spokes:&dyn Fn(i32) -> i32) -> Vec<Point> {
+    let mut grid = vec![Point { x: 0, y: 0 }];
+    for k in 1..(rings + 1) {
+        let r = (MAX * k) as f32 / rings as f32;
+        let n = spokes(k);
+        for a in 0..n {
+            let theta = 2.0 * std::f32::consts::PI * a as f32 / n as f32;
+            let p = Point {
+                x: (r * theta.cos()).round() as i32,
+                y: (r * theta.sin()).round() as i32
+            };
+            if !grid.contains(&p) {
+                grid.push(p);
+            }
+        }
+    }
+    grid
+}
+
+// draw pairs until there are 128 with distinct endpoints and none repeating
+// another either way round, as those add nothing to a descriptor
+fn distinct_pairs<R:Rng>(rng:&mut R, draw:&dyn Fn(&mut R) -> PairPoint) -> Vec<PairPoint> {
+    let mut pairs = Vec::<PairPoint>::with_capacity(128);
+    while pairs.len() < 128 {
+        let p = draw(rng);
+        if p.0 != p.1 && !pairs.iter().any(|q| (q.0 == p.0 && q.1 == p.1) || (q.0 == p.1 && q.1 == p.0)) {
+            pairs.push(p);
+        }
+    }
+    pairs
+}
+
+fn generate<R:Rng>(pattern:Pattern, rng:&mut R) -> Vec<PairPoint> {
+    let size = (2 * MAX) as f32;
+    match pattern {
+        Pattern::Uniform => {
+            let d = Uniform::new_inclusive(-MAX, MAX);
+            distinct_pairs(rng, &|rng| PairPoint::from(rng.sample(d), rng.sample(d), rng.sample(d), rng.sample(d)))
+        },
+        Pattern::Gaussian => {
+            let sigma = size / 5.0;
+            distinct_pairs(rng, &|rng| PairPoint::from(normal(rng, 0.0, sigma), normal(rng, 0.0, sigma),
+                                                       normal(rng, 0.0, sigma), normal(rng, 0.0, sigma)))
+        },
+        Pattern::CentredGaussian => {
+            distinct_pairs(rng, &|rng| {
+                let x = normal(rng, 0.0, size / 5.0);
+                let y = normal(rng, 0.0, size / 5.0);
+                let sigma = size / 10.0;
+                PairPoint::from(x, y,
+                                normal(rng, x as f32, sigma), normal(rng, y as f32, sigma))
+            })
+        },
+        Pattern::PolarGrid => {
+            let grid = polar_grid(4, &|_| 8);
+            distinct_pairs(rng, &|rng| {
+                let pair:Vec<&Point> = grid.choose_multiple(rng, 2).collect();
+                PairPoint(pair[0].clone(), pair[1].clone())
+            })
+        },
+        Pattern::CentredPolarGrid => {
+            // the points of the grid are distinct, so each pairs with the centre once
+            let grid = polar_grid(7, &|k| 5 * k);
+            assert!(grid.len() > 128, "centred polar grid has only {} points", grid.len());
+            grid[1..].choose_multiple(rng, 128)
+                .map(|p| PairPoint(Point { x: 0, y: 0 }, p.clone()))
+                .collect()
+        }
+    }
+}
 
 impl TestSet {
     pub fn new() -> TestSet {
         // 128 pairs of points in range -13 to +13
-        let mut set = Vec::<PairPoint>::new();
-        let mut rng = rand::thread_rng();
-        let d = Uniform::new_inclusive(-MAX, MAX);
-        let v: Vec<i32> = (&mut rng).sample_iter(d).take(128 * 4).collect(); 
-        for i in 0..128 {
-            set.push(PairPoint::from(
-                        v[i * 4], v[i * 4 + 1], v[i * 4 + 2], v[i * 4 + 3]));
+        TestSet {
+            set: generate(Pattern::Uniform, &mut rand::thread_rng()),
+            kernel: Kernel::default(),
+            provenance: "Uniform pattern".to_string()
         }
+    }
+
+    // as new, but reproducible
+    pub fn with_seed(seed:u64) -> TestSet {
+        TestSet::from_pattern(Pattern::Uniform, seed)
+    }
+
+    // an untrained set drawn from one of the BRIEF sampling patterns
+    pub fn from_pattern(pattern:Pattern, seed:u64) -> TestSet {
+        let mut rng = ChaCha8Rng::seed_from_u64(seed);
         TestSet {
-            set: set
+            set: generate(pattern, &mut rng),
+            kernel: Kernel::default(),
+            provenance: format!("{:?} pattern, seed {}", pattern, seed)
         }
     }
 
+    // the trained set embedded in the library
+    pub fn trained() -> TestSet {
+        TestSet::from_json(TRAINED_TEST_SET).expect("embedded test set is invalid")
+    }
+
+    pub fn header(&self) -> TestSetHeader {
+        TestSetHeader {
+            version: TEST_SET_VERSION,
+            descriptor_bits: self.set.len() as u32,
+            max_offset: MAX,
+            kernel: self.kernel,
+            provenance: self.provenance.clone()
+        }
+    }
+
+    // check the set can be used to describe a patch
+    pub fn validate(&self) -> Result<()> {
+        if self.set.is_empty() || self.set.len() > 128 {
+            return Err(Error::InvalidTestSet(format!("{} tests, expected 1 to 128", self.set.len())));
+        }
+        let inside = |p:&Point| p.x.abs() <= MAX && p.y.abs() <= MAX;
+        if let Some(p) = self.set.iter().find(|p| !inside(&p.0) || !inside(&p.1)) {
+            return Err(Error::InvalidTestSet(format!("test {:?} lies outside the patch", p)));
+        }
+        match self.kernel {
+            Kernel::Box(width) if width % 2 == 0 =>
+                Err(Error::InvalidTestSet(format!("box kernel width {} is not odd", width))),
+            Kernel::Gaussian(sigma) if !(sigma > 0.0 && sigma.is_finite()) =>
+                Err(Error::InvalidTestSet(format!("gaussian kernel sigma {} is not positive", sigma))),
+            _ => Ok(())
+        }
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(&VersionedTestSet {
+            header: self.header(),
+            set: self.set.clone()
+        })?)
+    }
+
+    pub fn from_json(serialized:&str) -> Result<TestSet> {
+        let deserialized = serde_json::from_str(serialized)?;
+        let set = match deserialized {
+            TestSetFile::Versioned(file) => {
+                let header = file.header;
+                if header.version > TEST_SET_VERSION {
+                    return Err(Error::Format(format!("unsupported test set version {}", header.version)));
+                }
+                if header.descriptor_bits as usize != file.set.len() {
+                    return Err(Error::Format(format!("test set header gives {} bits but has {} tests",
+                                                     header.descriptor_bits, file.set.len())));
+                }
+                if header.max_offset > MAX {
+                    return Err(Error::InvalidTestSet(format!("offsets up to {} don't fit in the patch",
+                                                             header.max_offset)));
+                }
+                TestSet {
+                    set: file.set,
+                    kernel: header.kernel,
+                    provenance: header.provenance
+                }
+            },
+            TestSetFile::Pairs(set) => TestSet {
+                set: set,
+                kernel: Kernel::default(),
+                provenance: String::new()
+            },
+            TestSetFile::TestSet(set) => set
+        };
+        set.validate()?;
+        Ok(set)
+    }
+
+    // the tests at the given indices, in that order, such as a pruned or
+    // reordered set
+    pub fn subset(&self, indices:&[usize]) -> TestSet {
+        TestSet {
+            set: indices.iter().map(|&i| self.set[i].clone()).collect(),
+            kernel: self.kernel,
+            provenance: format!("{} tests of {}", indices.len(), self.provenance)
+        }
+    }
+
+    // the compact form, a header followed by four i8 coordinates per test
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let header = self.header();
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(TEST_SET_MAGIC);
+        bytes.extend_from_slice(&header.version.to_le_bytes());
+        let (tag, value) = kernel_to_tag(header.kernel);
+        bytes.push(tag);
+        bytes.extend_from_slice(&value.to_le_bytes());
+        bytes.extend_from_slice(&header.descriptor_bits.to_le_bytes());
+        bytes.extend_from_slice(&header.max_offset.to_le_bytes());
+        bytes.extend_from_slice(&(header.provenance.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(header.provenance.as_bytes());
+        for p in self.set.iter() {
+            bytes.extend([p.0.x, p.0.y, p.1.x, p.1.y].iter().map(|&v| v as i8 as u8));
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes:&[u8]) -> Result<TestSet> {
+        let mut r = bytes;
+        let mut magic = [0u8; 4];
+        r.read_exact(&mut magic)?;
+        if &magic != TEST_SET_MAGIC {
+            return Err(Error::Format("not a binary rBRIEF test set".to_string()));
+        }
+        let mut word = [0u8; 4];
+        r.read_exact(&mut word)?;
+        let version = u32::from_le_bytes(word);
+        if version > TEST_SET_VERSION {
+            return Err(Error::Format(format!("unsupported test set version {}", version)));
+        }
+        let mut tag = [0u8; 1];
+        r.read_exact(&mut tag)?;
+        r.read_exact(&mut word)?;
+        let kernel = kernel_from_tag(tag[0], u32::from_le_bytes(word))?;
+        r.read_exact(&mut word)?;
+        let bits = u32::from_le_bytes(word) as usize;
+        if bits > 128 {
+            return Err(Error::InvalidTestSet(format!("{} tests don't fit in a descriptor", bits)));
+        }
+        r.read_exact(&mut word)?;
+        let max_offset = i32::from_le_bytes(word);
+        if max_offset > MAX {
+            return Err(Error::InvalidTestSet(format!("offsets up to {} don't fit in the patch", max_offset)));
+        }
+        r.read_exact(&mut word)?;
+        // check the lengths against what's left before allocating for them
+        let provenance_len = u32::from_le_bytes(word) as usize;
+        if provenance_len + bits * 4 != r.len() {
+            return Err(Error::Format(format!("test set of {} tests with {} bytes of provenance has {} bytes left",
+                                             bits, provenance_len, r.len())));
+        }
+        let mut provenance = vec![0u8; provenance_len];
+        r.read_exact(&mut provenance)?;
+        let mut coords = vec![0u8; bits * 4];
+        r.read_exact(&mut coords)?;
+        if !r.is_empty() {
+            return Err(Error::Format(format!("{} unexpected bytes after test set", r.len())));
+        }
+        let v = |i:usize| coords[i] as i8 as i32;
+        let set = TestSet {
+            set: (0..bits)
+                .map(|i| PairPoint::from(v(i * 4), v(i * 4 + 1), v(i * 4 + 2), v(i * 4 + 3)))
+                .collect(),
+            kernel: kernel,
+            provenance: String::from_utf8(provenance)?
+        };
+        set.validate()?;
+        Ok(set)
+    }
+
     pub fn save(&self, filename:&str) -> Result<()> {
-        let serialized = serde_json::to_string(&self.set)?;
-        fs::write(filename, serialized)?;
+        fs::write(filename, self.to_json()?)?;
+        Ok(())
+    }
+
+    pub fn save_binary(&self, filename:&str) -> Result<()> {
+        fs::write(filename, self.to_bytes())?;
         Ok(())
     }
 
+    // load either the JSON or binary form
     pub fn load(filename:&str) -> Result<TestSet> {
-        let serialized = fs::read_to_string(filename)?;
-        let deserialized = serde_json::from_str(&serialized)?;
-        Ok(TestSet {
-            set: deserialized
-        })
+        let bytes = fs::read(filename)?;
+        if bytes.starts_with(TEST_SET_MAGIC) {
+            TestSet::from_bytes(&bytes)
+        } else {
+            TestSet::from_json(std::str::from_utf8(&bytes)?)
+        }
+    }
+}
+
+fn kernel_to_tag(kernel:Kernel) -> (u8, u32) {
+    match kernel {
+        Kernel::Box(width) => (0u8, width),
+        Kernel::Gaussian(sigma) => (1u8, sigma.to_bits())
     }
 }
 
-fn describe_with_testset(image:&GrayIntegral, p:&Point, set: &TestSet) -> u128 {
+fn kernel_from_tag(tag:u8, value:u32) -> Result<Kernel> {
+    match tag {
+        0 => Ok(Kernel::Box(value)),
+        1 => Ok(Kernel::Gaussian(f32::from_bits(value))),
+        t => Err(Error::Format(format!("unknown kernel type {}", t)))
+    }
+}
+
+fn describe_with_testset(patch:&Patch, p:&Point, set: &TestSet) -> u128 {
     let mut d = 0u128;
-    for i in 0..128 {
-        if test(image, p, &set.set[i]) {
+    for (i, pair) in set.set.iter().enumerate() {
+        if test(patch, p, pair) {
             d |= 1 << i;
         }
     }
@@ -133,53 +516,256 @@ pub fn rotate(set:&TestSet, angle:f32) -> TestSet {
     TestSet {
         set: set.set.iter()
             .map(|p| p.rotate(c, s))
-            .collect()
+            .collect(),
+        kernel: set.kernel,
+        provenance: set.provenance.clone()
     }
 }
 
-fn make_integral_image(image:&GrayImage, x:u32, y:u32) -> Option<GrayIntegral> {
-    let r = RADIUS;
+// whether the whole patch of radius r around x, y lies within the image
+pub fn can_describe(image:&GrayImage, x:u32, y:u32, r:u32) -> bool {
     let (w, h) = image.dimensions();
-    if x < r || y < r || x + r > w || y + r > h {
+    x >= r && y >= r && x + r < w && y + r < h
+}
+
+fn gaussian_kernel(sigma:f32) -> Vec<f32> {
+    let r = Kernel::Gaussian(sigma).margin() as i32;
+    let kernel:Vec<f32> = (-r..(r + 1))
+        .map(|i| (-((i * i) as f32) / (2.0 * sigma * sigma)).exp())
+        .collect();
+    let sum:f32 = kernel.iter().sum();
+    kernel.iter().map(|k| k / sum).collect()
+}
+
+fn gaussian_smooth(image:&GrayImage, sigma:f32) -> GrayFloat {
+    let image = GrayFloat::from_fn(image.width(), image.height(),
+                                   |x, y| Luma([image.get_pixel(x, y)[0] as f32]));
+    filter::separable_filter_equal(&image, &gaussian_kernel(sigma))
+}
+
+fn box_patch(view:&GrayImage, width:u32) -> Patch {
+    Patch::Box(integral_image::integral_image::<_, u32>(view), (width / 2) as i32)
+}
+
+// crop the patch around x, y, returning None if it crosses the border.
+// test points are relative to the patch centre at (radius, radius). For a
+// gaussian kernel only the patch is smoothed, so describing many corners of one
+// image is better done through a KernelImage.
+pub fn make_patch(image:&GrayImage, x:u32, y:u32, kernel:Kernel) -> Option<Patch> {
+    let r = kernel.radius();
+    if !can_describe(image, x, y, r) {
         return None;
     }
     let view = imageops::crop_imm(image, x - r, y - r, 2 * r + 1, 2 * r + 1).to_image();
-    let integral = integral_image::integral_image::<_, u32>(&view);
-    Some(integral)
+    Some(match kernel {
+        Kernel::Box(width) => box_patch(&view, width),
+        Kernel::Gaussian(sigma) => Patch::Gaussian(gaussian_smooth(&view, sigma))
+    })
+}
+
+// an image to cut patches for a kernel from, such as a pyramid level. For a
+// gaussian kernel the whole image is smoothed once up front and patches are
+// cut from that.
+pub struct KernelImage<'a> {
+    image: Cow<'a, GrayImage>,
+    smoothed: Option<GrayFloat>,
+    kernel: Kernel
+}
+
+impl<'a> KernelImage<'a> {
+    pub fn new(image:Cow<'a, GrayImage>, kernel:Kernel) -> KernelImage<'a> {
+        let smoothed = match kernel {
+            Kernel::Box(_) => None,
+            Kernel::Gaussian(sigma) => Some(gaussian_smooth(&image, sigma))
+        };
+        KernelImage {
+            image: image,
+            smoothed: smoothed,
+            kernel: kernel
+        }
+    }
+
+    pub fn image(&self) -> &GrayImage {
+        &self.image
+    }
+
+    pub fn kernel(&self) -> Kernel {
+        self.kernel
+    }
+
+    // as make_patch, cut from the prepared image
+    pub fn patch(&self, x:u32, y:u32) -> Option<Patch> {
+        let r = self.kernel.radius();
+        if !can_describe(&self.image, x, y, r) {
+            return None;
+        }
+        let (left, top, size) = (x - r, y - r, 2 * r + 1);
+        Some(match (&self.smoothed, self.kernel) {
+            (Some(smoothed), _) => Patch::Gaussian(imageops::crop_imm(smoothed, left, top, size, size).to_image()),
+            (None, kernel) => box_patch(&imageops::crop_imm(&*self.image, left, top, size, size).to_image(),
+                                        2 * kernel.margin() + 1)
+        })
+    }
+}
+
+// test sets are pre-rotated to this many angles between 0 and pi
+const ANGLE_BINS:usize = 30;
+const ANGLE_PER_BIN:f32 = std::f32::consts::PI / ANGLE_BINS as f32;
+
+fn angle_bin(angle:f32) -> usize {
+    ((angle / ANGLE_PER_BIN + 0.5) as usize) % ANGLE_BINS
 }
 
 pub struct RBrief {
     sets: Vec<TestSet>,
-    angle_per_set: f32
+    kernel: Kernel
+}
+
+// how the test set of an RBrief is given in a config file, told apart by
+// their keys as toml only reads enums whose variants hold tables. A relative
+// file is found from the folder of the config file when loaded with Config::load.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum TestSetSource {
+    // "trained" for the set embedded in the library
+    Named(String),
+    File { file: String },
+    Pattern { pattern: Pattern, seed: u64 },
+    // the binary form as hex, which unlike Inline can be written to toml
+    Encoded { encoded: String },
+    Inline(TestSet)
+}
+
+thread_local! {
+    // the folder of the config file being loaded, which test set files it names are relative to
+    static CONFIG_DIR: RefCell<Option<PathBuf>> = const { RefCell::new(None) };
+}
+
+// run f, reading test set files named with relative paths from dir rather than
+// the working directory
+pub(crate) fn with_config_dir<T>(dir:&Path, f:impl FnOnce() -> T) -> T {
+    let previous = CONFIG_DIR.with(|d| d.replace(Some(dir.to_path_buf())));
+    let result = f();
+    CONFIG_DIR.with(|d| *d.borrow_mut() = previous);
+    result
+}
+
+fn config_path(file:&str) -> PathBuf {
+    CONFIG_DIR.with(|d| match d.borrow().as_ref() {
+        Some(dir) => dir.join(file),
+        None => PathBuf::from(file)
+    })
+}
+
+impl Serialize for RBrief {
+    fn serialize<S:Serializer>(&self, serializer:S) -> std::result::Result<S::Ok, S::Error> {
+        // parsed once, as configs are serialized for every hash and comparison
+        static TRAINED: OnceLock<TestSet> = OnceLock::new();
+        let set = &self.sets[0];
+        let trained = TRAINED.get_or_init(TestSet::trained);
+        if set.set == trained.set && set.kernel == trained.kernel {
+            TestSetSource::Named("trained".to_string()).serialize(serializer)
+        } else {
+            let hex:String = set.to_bytes().iter().map(|b| format!("{:02x}", b)).collect();
+            TestSetSource::Encoded { encoded: hex }.serialize(serializer)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for RBrief {
+    fn deserialize<D:Deserializer<'de>>(deserializer:D) -> std::result::Result<RBrief, D::Error> {
+        let set = match TestSetSource::deserialize(deserializer)? {
+            TestSetSource::Named(name) if name == "trained" => TestSet::trained(),
+            TestSetSource::Named(name) => return Err(de::Error::custom(format!("unknown test set {}", name))),
+            TestSetSource::File { file } => {
+                let path = config_path(&file);
+                TestSet::load(&path.to_string_lossy())
+                    .map_err(|e| de::Error::custom(format!("couldn't load {}: {}", path.display(), e)))?
+            },
+            TestSetSource::Pattern { pattern, seed } => TestSet::from_pattern(pattern, seed),
+            TestSetSource::Encoded { encoded } => {
+                let bytes = (0..encoded.len() / 2)
+                    .map(|i| encoded.get(i * 2..i * 2 + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+                    .collect::<Option<Vec<u8>>>()
+                    .ok_or_else(|| de::Error::custom("invalid hex test set"))?;
+                TestSet::from_bytes(&bytes).map_err(de::Error::custom)?
+            },
+            TestSetSource::Inline(set) => {
+                set.validate().map_err(de::Error::custom)?;
+                set
+            }
+        };
+        Ok(RBrief::from_test_set(set))
+    }
 }
 
 impl RBrief {
     pub fn from_test_set(set:TestSet) -> RBrief {
+        let kernel = set.kernel;
         let mut sets = Vec::<TestSet>::new();
         sets.push(set);
-        let alpha = std::f32::consts::PI / 30.0;
-        for i in 1..30 {
-            sets.push(rotate(&sets[0], i as f32 * alpha))
+        for i in 1..ANGLE_BINS {
+            sets.push(rotate(&sets[0], i as f32 * ANGLE_PER_BIN))
         }
         RBrief {
             sets: sets,
-            angle_per_set: alpha
+            kernel: kernel
         }
     }
 
+    pub fn kernel(&self) -> Kernel {
+        self.kernel
+    }
+
+    pub fn radius(&self) -> u32 {
+        self.kernel.radius()
+    }
+
+    pub fn can_describe(&self, image:&GrayImage, x:u32, y:u32) -> bool {
+        can_describe(image, x, y, self.radius())
+    }
+
     pub fn new() -> RBrief {
         RBrief::from_test_set(TestSet::new())
     }
 
+    pub fn with_seed(seed:u64) -> RBrief {
+        RBrief::from_test_set(TestSet::with_seed(seed))
+    }
+
     pub fn describe(&self, image:&GrayImage, x:u32, y:u32, angle:f32) -> Option<u128> {
-        let index = ((angle / self.angle_per_set + 0.5) as usize) % self.sets.len();
-        let r = RADIUS;
-        if let Some(integral) = make_integral_image(image, x, y) {
-            let d = describe_with_testset(&integral, &Point{x:r as i32, y:r as i32}, &self.sets[index]);
-            Some(d)
-        } else { 
-            None
-        }
+        self.describe_with_set(image, x, y, angle_bin(angle))
+    }
+
+    // describe using the unrotated test set, for when the orientation is known
+    // to be close to zero
+    pub fn describe_upright(&self, image:&GrayImage, x:u32, y:u32) -> Option<u128> {
+        self.describe_with_set(image, x, y, 0)
+    }
+
+    // the test set rotated to the bin used for angle
+    pub fn test_set(&self, angle:f32) -> &TestSet {
+        &self.sets[angle_bin(angle)]
+    }
+
+    // as describe, from an image prepared with this test set's kernel
+    pub fn describe_prepared(&self, image:&KernelImage, x:u32, y:u32, angle:f32) -> Option<u128> {
+        debug_assert_eq!(image.kernel(), self.kernel);
+        self.describe_patch(image.patch(x, y), angle_bin(angle))
+    }
+
+    pub fn describe_upright_prepared(&self, image:&KernelImage, x:u32, y:u32) -> Option<u128> {
+        debug_assert_eq!(image.kernel(), self.kernel);
+        self.describe_patch(image.patch(x, y), 0)
+    }
+
+    fn describe_with_set(&self, image:&GrayImage, x:u32, y:u32, index:usize) -> Option<u128> {
+        self.describe_patch(make_patch(image, x, y, self.kernel), index)
+    }
+
+    fn describe_patch(&self, patch:Option<Patch>, index:usize) -> Option<u128> {
+        let r = self.radius() as i32;
+        patch.map(|patch| describe_with_testset(&patch, &Point{x:r, y:r}, &self.sets[index]))
     }
 }
 
@@ -228,140 +814,797 @@ pub const WINDOW:i32 = HWINDOW as i32 * 2 + 1;
 pub const MAX:i32 = (HWIDTH - HWINDOW) as i32;
 pub const RADIUS:u32 = (HWIDTH as f64 * std::f64::consts::SQRT_2) as u32 + HWINDOW;
 
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq)]
 struct BitVec {
-    vec:Vec<u8>,
-    bit:u8
+    words:Vec<u64>,
+    len:usize
 }
 
 impl BitVec {
     fn new() -> BitVec {
         BitVec {
-            vec: vec![0u8],
-            bit: 0
+            words: Vec::new(),
+            len: 0
         }
     }
 
+    #[cfg(test)]
     fn push(&mut self, a:bool) {
-        let v = if a { 1 } else { 0 };
-        let i = self.vec.len() - 1;
-        self.vec[i] |= v << self.bit;
-        if self.bit < 7 {
-            self.bit += 1;
+        self.push_word(if a { 1 } else { 0 }, 1);
+    }
+
+    // push the n least significant bits of word
+    fn push_word(&mut self, word:u64, n:usize) {
+        if n == 0 {
+            return;
+        }
+        let word = if n < 64 { word & ((1u64 << n) - 1) } else { word };
+        let shift = self.len % 64;
+        if shift == 0 {
+            self.words.push(word);
         } else {
-            self.vec.push(0u8);
-            self.bit = 0;
+            let i = self.words.len() - 1;
+            self.words[i] |= word << shift;
+            if shift + n > 64 {
+                self.words.push(word >> (64 - shift));
+            }
+        }
+        self.len += n;
+    }
+
+    // the first len bits of bytes, least significant bit first
+    fn from_bytes(bytes:Vec<u8>, len:usize) -> BitVec {
+        let mut b = BitVec::new();
+        for (i, chunk) in bytes.chunks(8).enumerate() {
+            if i * 64 >= len {
+                break;
+            }
+            let mut word = [0u8; 8];
+            word[..chunk.len()].copy_from_slice(chunk);
+            b.push_word(u64::from_le_bytes(word), std::cmp::min(64, len - i * 64));
+        }
+        b
+    }
+
+    fn bytes(&self) -> Vec<u8> {
+        let mut bytes:Vec<u8> = self.words.iter()
+            .flat_map(|w| w.to_le_bytes().to_vec())
+            .collect();
+        bytes.truncate((self.len + 7) / 8);
+        bytes
+    }
+
+    fn append(&mut self, other:&BitVec) {
+        let mut remaining = other.len;
+        for w in other.words.iter() {
+            let n = std::cmp::min(64, remaining);
+            self.push_word(*w, n);
+            remaining -= n;
         }
     }
 
     fn len(&self) -> usize {
-        (self.vec.len() - 1) * 8 + self.bit as usize
+        self.len
     }
 
     fn mean(&self) -> f32 {
-        hamming::weight(&self.vec) as f32 /
+        if self.len() == 0 {
+            return 0.0;
+        }
+        self.words.iter().map(|w| w.count_ones()).sum::<u32>() as f32 /
             self.len() as f32
     }
 
     fn correlation(&self, b:&BitVec) -> f32 {
-        1.0 - (hamming::distance(&self.vec, &b.vec) as f32 /
-            self.len() as f32)
+        if self.len() == 0 {
+            return 0.0;
+        }
+        let distance = self.words.iter().zip(b.words.iter())
+            .map(|(x, y)| (x ^ y).count_ones())
+            .sum::<u32>();
+        1.0 - (distance as f32 / self.len() as f32)
     }
 }
 
-pub struct Trainer {
-    scores:Vec<BitVec>
+// the pair table rotated to each of the angles RBrief describes at, with
+// coordinates packed as x0, y0, x1, y1
+fn rotated_pair_table() -> Vec<Vec<[i8; 4]>> {
+    let pairs:Vec<PairPoint> = PairPoint::all_pairs().collect();
+    (0..ANGLE_BINS).map(|bin| {
+        let angle = bin as f32 * ANGLE_PER_BIN;
+        let (c, s) = (f32::cos(angle), f32::sin(angle));
+        pairs.iter()
+            .map(|p| {
+                let PairPoint(a, b) = p.rotate(c, s);
+                [a.x as i8, a.y as i8, b.x as i8, b.y as i8]
+            })
+            .collect()
+    }).collect()
 }
 
-impl Trainer {
-    pub fn new() -> Trainer {
-        let c = PairPoint::all_pairs().count();
-        Trainer {
-            scores: vec![BitVec::new(); c]
-        }
+// zero for an empty slice, so reports never hold NaN, which JSON writes as null
+fn mean_stddev(v:&[f32]) -> (f32, f32) {
+    if v.is_empty() {
+        return (0.0, 0.0);
     }
+    let mean = v.iter().sum::<f32>() / v.len() as f32;
+    let var = v.iter().fold(0.0, |s, b| s + (b - mean) * (b - mean)) / v.len() as f32;
+    (mean, var.sqrt())
+}
 
-    pub fn accumulate(&mut self, image:&GrayImage, x:u32, y:u32, angle:f32) {
-        let alpha = std::f32::consts::PI / 30.0;
-        let angle = ((angle / alpha + 0.5) as usize) as f32 * alpha;
-        let r = RADIUS as i32;
-        if let Some(integral) = make_integral_image(image, x, y) {
-            for (i, pair) in PairPoint::all_pairs().enumerate() {
-                let c = f32::cos(angle);
-                let s = f32::sin(angle);
-                let pair = pair.rotate(c, s);
-                self.scores[i].push(test(&integral, &Point{x:r, y:r}, &pair));
-            }
+// the number of tests kept at each step of the threshold search
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ThresholdStep {
+    pub threshold: f32,
+    pub collected: usize
+}
+
+// the mean and variance of one test over the training keypoints
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TestStatistics {
+    pub mean: f32,
+    pub variance: f32,
+    // how often the test changes between matching patches, from supervised training
+    #[serde(default)]
+    pub flip_rate: Option<f32>
+}
+
+// how one test of a set behaved on the training keypoints, with its largest
+// correlation against the other tests of the set
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PairStatistics {
+    pub mean: f32,
+    pub max_correlation: f32
+}
+
+// statistics over a set of tests, where correlation is the fraction of
+// keypoints on which a pair of tests agree
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SetStatistics {
+    pub mean_distance_from_half: f32,
+    pub stddev_distance_from_half: f32,
+    pub mean_correlation: f32,
+    pub stddev_correlation: f32,
+    pub max_correlation: f32
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TrainingReport {
+    pub selection: String,
+    pub samples: usize,
+    pub thresholds: Vec<ThresholdStep>,
+    pub tests: Vec<TestStatistics>,
+    pub selected: SetStatistics,
+    // a random sample of all tests, the same size as the selection
+    pub random: SetStatistics
+}
+
+impl TrainingReport {
+    pub fn save(&self, filename:&str) -> Result<()> {
+        let serialized = serde_json::to_string_pretty(self)?;
+        fs::write(filename, serialized)?;
+        Ok(())
+    }
+
+    pub fn load(filename:&str) -> Result<TrainingReport> {
+        Ok(serde_json::from_str(&fs::read_to_string(filename)?)?)
+    }
+}
+
+// a candidate test and its results over the training keypoints
+pub struct Candidate<'a> {
+    scores: &'a BitVec
+}
+
+impl<'a> Candidate<'a> {
+    pub fn mean(&self) -> f32 {
+        self.scores.mean()
+    }
+
+    // the fraction of keypoints on which the two tests agree
+    pub fn correlation(&self, other:&Candidate) -> f32 {
+        self.scores.correlation(other.scores)
+    }
+}
+
+// an algorithm for choosing a decorrelated set of tests
+pub trait Selection {
+    fn name(&self) -> String;
+
+    // choose count of the candidates, which are ordered best first, returning
+    // their indices and a trace of the thresholds tried
+    fn select(&self, candidates:&[Candidate], count:usize) -> Result<(Vec<usize>, Vec<ThresholdStep>)>;
+}
+
+fn check_candidates(available:usize, count:usize) -> Result<()> {
+    if available == 0 || available < count {
+        return Err(Error::InvalidTestSet(format!("can't select {} tests from {} candidates", count, available)));
+    }
+    Ok(())
+}
+
+// the greedy search from the ORB paper: keep each candidate whose mean
+// correlation with the kept tests is under a threshold, raising the threshold
+// until enough are kept then refining it by bisection
+#[derive(Clone, Debug)]
+pub struct GreedyThreshold {
+    pub initial: f32,
+    pub delta: f32,
+    pub iterations: u32
+}
+
+impl Default for GreedyThreshold {
+    fn default() -> GreedyThreshold {
+        GreedyThreshold {
+            initial: 0.4,
+            delta: 0.01,
+            iterations: 5
         }
     }
+}
 
-    pub fn make_test_set(&self) -> TestSet {
-        let mut threshold = 0.4;
-        let mut r = Vec::<(PairPoint, &BitVec)>::new();
-        let mut sorted = Vec::<(PairPoint, &BitVec)>::new();
-        for (i, pair) in PairPoint::all_pairs().enumerate() {
-            sorted.push((pair, &self.scores[i]));
+impl GreedyThreshold {
+    // mean correlations lie between 0 and 1, so a threshold raised past 1 that
+    // still can't collect count tests never will, and one lowered below 0
+    // can't collect fewer
+    fn search(&self, count:usize, pass:&mut dyn FnMut(f32) -> Vec<usize>)
+        -> Result<(Vec<usize>, Vec<ThresholdStep>)> {
+        if self.delta.is_nan() || self.delta <= 0.0 {
+            return Err(Error::InvalidTestSet(format!("threshold step {} isn't positive", self.delta)));
         }
-        sorted.sort_by_key(|k| OrderedFloat((0.5 - k.1.mean()).abs()));
-        //reverse list so we can "pop" off the end
-        sorted.reverse();
-      
-        let mut delta = 0.01;
+        let mut threshold = self.initial;
+        let mut thresholds = Vec::<ThresholdStep>::new();
+        let mut r = Vec::<usize>::new();
+        let mut delta = self.delta;
         let mut up = true;
-        for _i in 0..5 {
+        for _i in 0..self.iterations {
             threshold += delta;
             loop {
-                let mut t = sorted.clone();
-                r = Vec::<(PairPoint, &BitVec)>::new();
-                r.push(t.pop().unwrap());
-                while r.len() < 128 && t.len() > 0 {
-                    let a = t.pop().unwrap();
-                    let c = r.iter().fold(0.0, |s, b| s + b.1.correlation(a.1)) / r.len() as f32;
-                    if c < threshold {
-                        r.push(a);
-                    }
-                }
-                println!("threshold {} collected {} tests", threshold, r.len());
-                if (up && r.len() == 128) || (!up && r.len() < 128) {
+                r = pass(threshold);
+                thresholds.push(ThresholdStep {
+                    threshold: threshold,
+                    collected: r.len()
+                });
+                if (up && r.len() == count) || (!up && (r.len() < count || threshold < 0.0)) {
                     break;
                 }
+                if up && threshold > 1.0 {
+                    return Err(Error::InvalidTestSet(format!(
+                        "only {} of {} tests are decorrelated enough to select", r.len(), count)));
+                }
                 threshold += delta;
             }
             delta = -delta / 2.0;
             up = !up;
         }
+        // with no passes, or an even number ending on a lowered threshold, keep
+        // raising it by the last step until count are collected
+        let step = delta.abs();
+        while r.len() != count {
+            r = pass(threshold);
+            thresholds.push(ThresholdStep {
+                threshold: threshold,
+                collected: r.len()
+            });
+            if r.len() != count && threshold > 1.0 {
+                return Err(Error::InvalidTestSet(format!(
+                    "only {} of {} tests are decorrelated enough to select", r.len(), count)));
+            }
+            threshold += step;
+        }
+        Ok((r, thresholds))
+    }
+}
+
+impl Selection for GreedyThreshold {
+    fn name(&self) -> String {
+        "greedy".to_string()
+    }
+
+    fn select(&self, candidates:&[Candidate], count:usize) -> Result<(Vec<usize>, Vec<ThresholdStep>)> {
+        check_candidates(candidates.len(), count)?;
+        self.search(count, &mut |threshold| {
+            let mut r = vec![0usize];
+            for (i, a) in candidates.iter().enumerate().skip(1) {
+                if r.len() == count {
+                    break;
+                }
+                let c = r.iter().fold(0.0, |s, &b| s + candidates[b].correlation(a)) / r.len() as f32;
+                if c < threshold {
+                    r.push(i);
+                }
+            }
+            r
+        })
+    }
+}
+
+// the same search as GreedyThreshold, but each candidate keeps its
+// correlations with the kept tests between passes, so a pass only computes
+// correlations with tests that differ from the previous pass
+#[derive(Clone, Debug, Default)]
+pub struct IncrementalGreedyThreshold {
+    pub search: GreedyThreshold
+}
+
+impl Selection for IncrementalGreedyThreshold {
+    fn name(&self) -> String {
+        "incremental".to_string()
+    }
+
+    fn select(&self, candidates:&[Candidate], count:usize) -> Result<(Vec<usize>, Vec<ThresholdStep>)> {
+        check_candidates(candidates.len(), count)?;
+        // cache[i][k] is the correlation of candidate i with prev[k]
+        let mut cache = vec![Vec::<f32>::new(); candidates.len()];
+        let mut touched = Vec::<usize>::new();
+        let mut prev = Vec::<usize>::new();
+        self.search.search(count, &mut |threshold| {
+            let mut r = vec![0usize];
+            // the length of the prefix r shares with prev
+            let mut common = if prev.first() == Some(&0) { 1 } else { 0 };
+            let mut last = 0;
+            for (i, a) in candidates.iter().enumerate().skip(1) {
+                if r.len() == count {
+                    break;
+                }
+                last = i;
+                let c = &mut cache[i];
+                if c.is_empty() {
+                    touched.push(i);
+                }
+                c.truncate(common);
+                for &b in r[c.len()..].iter() {
+                    c.push(candidates[b].correlation(a));
+                }
+                let mean = c.iter().sum::<f32>() / r.len() as f32;
+                if mean < threshold {
+                    if common == r.len() && prev.get(common) == Some(&i) {
+                        common += 1;
+                    }
+                    r.push(i);
+                }
+            }
+            // candidates this pass didn't reach still refer to prev
+            for &i in touched.iter().filter(|&&i| i > last) {
+                cache[i].truncate(common);
+            }
+            prev = r.clone();
+            r
+        })
+    }
+}
+
+// choose exactly count tests, each time taking the candidate whose largest
+// correlation with the kept tests is smallest. correlation is measured as the
+// distance from 0.5 agreement so anti-correlated tests count as redundant, and
+// only the best pool candidates are considered
+#[derive(Clone, Debug)]
+pub struct MinMaxGreedy {
+    pub pool: usize
+}
+
+impl Default for MinMaxGreedy {
+    fn default() -> MinMaxGreedy {
+        MinMaxGreedy {
+            pool: 4096
+        }
+    }
+}
 
-        fn stat(v:&Vec<(PairPoint, &BitVec)>) {
+impl Selection for MinMaxGreedy {
+    fn name(&self) -> String {
+        "minmax".to_string()
+    }
+
+    fn select(&self, candidates:&[Candidate], count:usize) -> Result<(Vec<usize>, Vec<ThresholdStep>)> {
+        let pool = &candidates[..std::cmp::min(self.pool, candidates.len())];
+        check_candidates(pool.len(), count)?;
+        let mut max_correlation = vec![0.0f32; pool.len()];
+        let mut available = vec![true; pool.len()];
+        let mut r = Vec::<usize>::new();
+        let mut thresholds = Vec::<ThresholdStep>::new();
+        let mut next = Some(0);
+        while let Some(n) = next {
+            r.push(n);
+            available[n] = false;
+            thresholds.push(ThresholdStep {
+                threshold: max_correlation[n],
+                collected: r.len()
+            });
+            if r.len() == count {
+                break;
+            }
+            next = None;
+            let mut best = std::f32::MAX;
+            for (i, a) in pool.iter().enumerate() {
+                if !available[i] {
+                    continue;
+                }
+                let c = (pool[n].correlation(a) - 0.5).abs();
+                max_correlation[i] = max_correlation[i].max(c);
+                // ties go to the earlier, better balanced candidate
+                if max_correlation[i] < best {
+                    best = max_correlation[i];
+                    next = Some(i);
+                }
+            }
+        }
+        Ok((r, thresholds))
+    }
+}
+
+// the results of pair i on up to 64 patches, each with the angle bin it is
+// described at
+fn test_word(rotated:&[Vec<[i8; 4]>], batch:&[(Patch, usize)], i:usize, r:i32) -> u64 {
+    let mut word = 0u64;
+    for (k, (patch, bin)) in batch.iter().enumerate() {
+        let [x0, y0, x1, y1] = rotated[*bin][i];
+        let pair = PairPoint::from(x0 as i32, y0 as i32, x1 as i32, y1 as i32);
+        if test(patch, &Point{x:r, y:r}, &pair) {
+            word |= 1 << k;
+        }
+    }
+    word
+}
+
+const TRAINER_MAGIC:&[u8; 4] = b"RBTR";
+const TRAINER_VERSION:u32 = 1;
+
+pub struct Trainer {
+    scores:Vec<BitVec>,
+    kernel:Kernel,
+    rotated:Vec<Vec<[i8; 4]>>
+}
+
+impl Trainer {
+    pub fn new() -> Trainer {
+        Trainer::with_kernel(Kernel::default())
+    }
+
+    // train with the kernel the resulting test set will be described with
+    pub fn with_kernel(kernel:Kernel) -> Trainer {
+        let c = PairPoint::all_pairs().count();
+        Trainer {
+            scores: vec![BitVec::new(); c],
+            kernel: kernel,
+            rotated: rotated_pair_table()
+        }
+    }
+
+    pub fn kernel(&self) -> Kernel {
+        self.kernel
+    }
+
+    // the number of keypoints accumulated so far
+    pub fn samples(&self) -> usize {
+        self.scores[0].len()
+    }
+
+    // save the accumulated scores so training can be resumed or merged. The file
+    // is written alongside then renamed so an interrupted save leaves the last one intact.
+    pub fn save(&self, filename:&str) -> Result<()> {
+        let partial = format!("{}.tmp", filename);
+        self.write(&partial)?;
+        fs::rename(&partial, filename)?;
+        Ok(())
+    }
+
+    fn write(&self, filename:&str) -> Result<()> {
+        let mut w = BufWriter::new(fs::File::create(filename)?);
+        w.write_all(TRAINER_MAGIC)?;
+        w.write_all(&TRAINER_VERSION.to_le_bytes())?;
+        let (tag, value) = kernel_to_tag(self.kernel);
+        w.write_all(&[tag])?;
+        w.write_all(&value.to_le_bytes())?;
+        w.write_all(&(self.scores.len() as u32).to_le_bytes())?;
+        w.write_all(&(self.samples() as u64).to_le_bytes())?;
+        for score in self.scores.iter() {
+            w.write_all(&score.bytes())?;
+        }
+        w.flush()?;
+        Ok(())
+    }
+
+    pub fn load(filename:&str) -> Result<Trainer> {
+        let file = fs::File::open(filename)?;
+        let len = file.metadata()?.len();
+        let mut r = BufReader::new(file);
+        let mut magic = [0u8; 4];
+        r.read_exact(&mut magic)?;
+        if &magic != TRAINER_MAGIC {
+            return Err(Error::Format(format!("{} is not an rBRIEF trainer file", filename)));
+        }
+        let mut word = [0u8; 4];
+        r.read_exact(&mut word)?;
+        let version = u32::from_le_bytes(word);
+        if version != TRAINER_VERSION {
+            return Err(Error::Format(format!("unsupported trainer file version {}", version)));
+        }
+        let mut tag = [0u8; 1];
+        r.read_exact(&mut tag)?;
+        r.read_exact(&mut word)?;
+        let kernel = kernel_from_tag(tag[0], u32::from_le_bytes(word))?;
+        r.read_exact(&mut word)?;
+        let count = u32::from_le_bytes(word) as usize;
+        if count != PairPoint::all_pairs().count() {
+            return Err(Error::Format(format!("trainer file has {} pairs", count)));
+        }
+        let mut long = [0u8; 8];
+        r.read_exact(&mut long)?;
+        let samples = u64::from_le_bytes(long);
+        // magic, version, kernel tag and size, count and samples, then the scores of each pair
+        let expected = samples.checked_add(7)
+            .and_then(|s| (s / 8).checked_mul(count as u64))
+            .and_then(|s| s.checked_add(25));
+        if expected != Some(len) {
+            return Err(Error::Format(format!("trainer file of {} bytes can't hold {} samples", len, samples)));
+        }
+        let samples = samples as usize;
+        let mut scores = Vec::<BitVec>::with_capacity(count);
+        for _i in 0..count {
+            let mut bytes = vec![0u8; (samples + 7) / 8];
+            r.read_exact(&mut bytes)?;
+            scores.push(BitVec::from_bytes(bytes, samples));
+        }
+        Ok(Trainer {
+            scores: scores,
+            kernel: kernel,
+            rotated: rotated_pair_table()
+        })
+    }
+
+    // combine the scores of a trainer run over different images
+    pub fn merge(&mut self, other:&Trainer) -> Result<()> {
+        if self.kernel != other.kernel {
+            return Err(Error::Incompatible(format!("can't merge trainers using kernels {:?} and {:?}",
+                                                   self.kernel, other.kernel)));
+        }
+        for (a, b) in self.scores.iter_mut().zip(other.scores.iter()) {
+            a.append(b);
+        }
+        Ok(())
+    }
+
+    // accumulate a single keypoint, smoothing only its patch
+    pub fn accumulate(&mut self, image:&GrayImage, x:u32, y:u32, angle:f32) {
+        let patch = make_patch(image, x, y, self.kernel).map(|p| (p, angle_bin(angle)));
+        self.accumulate_patches(patch.into_iter().collect());
+    }
+
+    // accumulate the tests for the keypoints (x, y, angle) of one image,
+    // 64 keypoints at a time, the pairs shared out between threads with the
+    // parallel feature
+    pub fn accumulate_batch(&mut self, image:&GrayImage, keypoints:&[(u32, u32, f32)]) {
+        self.accumulate_prepared(&KernelImage::new(Cow::Borrowed(image), self.kernel), keypoints);
+    }
+
+    // as accumulate_batch, from an image prepared with the trainer's kernel
+    pub fn accumulate_prepared(&mut self, image:&KernelImage, keypoints:&[(u32, u32, f32)]) {
+        debug_assert_eq!(image.kernel(), self.kernel);
+        let patches:Vec<(Patch, usize)> = maybe_par_iter!(keypoints)
+            .filter_map(|&(x, y, angle)| image.patch(x, y).map(|p| (p, angle_bin(angle))))
+            .collect();
+        self.accumulate_patches(patches);
+    }
+
+    fn accumulate_patches(&mut self, patches:Vec<(Patch, usize)>) {
+        let r = self.kernel.radius() as i32;
+        let rotated = &self.rotated;
+        for batch in patches.chunks(64) {
+            maybe_par_iter!(&mut self.scores).enumerate().for_each(|(i, score)| {
+                score.push_word(test_word(rotated, batch, i, r), batch.len());
+            });
+        }
+    }
+
+    pub fn make_test_set(&self) -> Result<(TestSet, TrainingReport)> {
+        self.make_test_set_with(&GreedyThreshold::default(), 0)
+    }
+
+    // the accumulated statistics of each test in set, or None for tests that
+    // aren't among the trained pairs. A test with its points swapped is the
+    // inverse of the trained pair.
+    pub fn test_statistics(&self, set:&TestSet) -> Vec<Option<PairStatistics>> {
+        let key = |p:&PairPoint| [p.0.x, p.0.y, p.1.x, p.1.y];
+        let index:HashMap<[i32; 4], usize> = PairPoint::all_pairs()
+            .enumerate()
+            .map(|(i, p)| (key(&p), i))
+            .collect();
+        let found:Vec<Option<(&BitVec, bool)>> = set.set.iter()
+            .map(|p| index.get(&key(p)).map(|&i| (&self.scores[i], false))
+                 .or_else(|| index.get(&key(&PairPoint(p.1.clone(), p.0.clone())))
+                          .map(|&i| (&self.scores[i], true))))
+            .collect();
+        found.iter().enumerate()
+            .map(|(i, a)| a.map(|(scores, inverted)| {
+                let mean = scores.mean();
+                let max_correlation = found.iter().enumerate()
+                    .filter(|(j, _b)| *j != i)
+                    .filter_map(|(_j, b)| b.map(|(other, other_inverted)| {
+                        let c = scores.correlation(other);
+                        if inverted != other_inverted { 1.0 - c } else { c }
+                    }))
+                    .fold(0.0, f32::max);
+                PairStatistics {
+                    mean: if inverted { 1.0 - mean } else { mean },
+                    max_correlation: max_correlation
+                }
+            }))
+            .collect()
+    }
+
+    // the seed picks the random sample of tests the selection is compared with
+    pub fn make_test_set_with(&self, selection:&dyn Selection, seed:u64) -> Result<(TestSet, TrainingReport)> {
+        // prefer tests with a mean close to 0.5
+        let mut order:Vec<usize> = (0..self.scores.len()).collect();
+        order.sort_by_key(|&i| OrderedFloat((0.5 - self.scores[i].mean()).abs()));
+        self.select_tests(&order, selection, None, seed)
+    }
+
+    // select from the pairs in order of preference
+    fn select_tests(&self, order:&[usize], selection:&dyn Selection, flip_rates:Option<&[f32]>,
+                    seed:u64) -> Result<(TestSet, TrainingReport)> {
+        if self.samples() == 0 {
+            return Err(Error::NoSamples);
+        }
+        let pairs:Vec<PairPoint> = PairPoint::all_pairs().collect();
+        let sorted:Vec<(PairPoint, &BitVec)> = order.iter()
+            .map(|&i| (pairs[i].clone(), &self.scores[i]))
+            .collect();
+        let candidates:Vec<Candidate> = sorted.iter()
+            .map(|(_p, b)| Candidate { scores: b })
+            .collect();
+        let (selected, thresholds) = selection.select(&candidates, 128)?;
+        if selected.len() != 128 {
+            return Err(Error::InvalidTestSet(format!("{} selection chose {} of 128 tests",
+                                                     selection.name(), selected.len())));
+        }
+        let r:Vec<(PairPoint, &BitVec)> = selected.iter()
+            .map(|&i| sorted[i].clone())
+            .collect();
+
+        fn stat(v:&Vec<(PairPoint, &BitVec)>) -> SetStatistics {
             let dist:Vec<f32> = v.iter().map(|b| (0.5 - b.1.mean()).abs()).collect();
-            let mean = dist.iter().sum::<f32>() / dist.len() as f32;
-            let var = dist.iter().fold(0.0, |s, b| s + (b - mean) * (b - mean)) / dist.len() as f32;
-            let stddev = var.sqrt();
-            println!("distance from 0.5 mean: {} stddev: {}", mean, stddev);
-            let mut correlation = 0.0;
+            let (mean, stddev) = mean_stddev(&dist);
+            let mut correlations = Vec::<f32>::new();
             for (i, a) in v.iter().enumerate() {
-                correlation += v.iter().enumerate()
-                                .fold(0.0, |s, (j, b)|
-                                    if i != j {
-                                        s + a.1.correlation(b.1)
-                                    } else { s } ) / (v.len() - 1) as f32;
+                for b in v[(i + 1)..].iter() {
+                    correlations.push(a.1.correlation(b.1));
+                }
+            }
+            let (correlation, correlation_stddev) = mean_stddev(&correlations);
+            SetStatistics {
+                mean_distance_from_half: mean,
+                stddev_distance_from_half: stddev,
+                mean_correlation: correlation,
+                stddev_correlation: correlation_stddev,
+                max_correlation: correlations.iter().cloned().fold(0.0, f32::max)
             }
-            correlation /= v.len() as f32;
-            println!("mean correlation: {}", correlation);
         }
 
-        println!("of collected tests:");
-        stat(&r);
-
-        println!("of a random sample of all tests:");
-        let mut rng = &mut rand::thread_rng();
+        let mut rng = ChaCha8Rng::seed_from_u64(seed);
         let sample = sorted.choose_multiple(&mut rng, r.len()).cloned().collect();
-        stat(&sample);
+
+        let report = TrainingReport {
+            selection: selection.name(),
+            samples: self.samples(),
+            thresholds: thresholds,
+            tests: selected.iter()
+                .map(|&i| {
+                    let mean = sorted[i].1.mean();
+                    TestStatistics {
+                        mean: mean,
+                        variance: mean * (1.0 - mean),
+                        flip_rate: flip_rates.map(|f| f[order[i]])
+                    }
+                })
+                .collect(),
+            selected: stat(&r),
+            random: stat(&sample)
+        };
 
         let tests = r.iter().map(|(p, _b)| p.clone()).collect();
-        TestSet {
-            set: tests
+        let provenance = format!("{}trained with {} on {} keypoints, seed {}",
+                                 if flip_rates.is_some() { "supervised, " } else { "" },
+                                 report.selection, report.samples, seed);
+        Ok((TestSet {
+            set: tests,
+            kernel: self.kernel,
+            provenance: provenance
+        }, report))
+    }
+}
+
+// trains on pairs of patches known to show the same point, such as a corner
+// and its location in a warped copy of the image, preferring tests that give
+// the same result on both as well as being balanced and decorrelated
+pub struct SupervisedTrainer {
+    trainer:Trainer,
+    flips:Vec<u32>,
+    pairs:usize
+}
+
+impl SupervisedTrainer {
+    pub fn new() -> SupervisedTrainer {
+        SupervisedTrainer::with_kernel(Kernel::default())
+    }
+
+    pub fn with_kernel(kernel:Kernel) -> SupervisedTrainer {
+        let trainer = Trainer::with_kernel(kernel);
+        let c = trainer.scores.len();
+        SupervisedTrainer {
+            trainer: trainer,
+            flips: vec![0; c],
+            pairs: 0
+        }
+    }
+
+    pub fn kernel(&self) -> Kernel {
+        self.trainer.kernel
+    }
+
+    // the number of matching pairs accumulated so far
+    pub fn samples(&self) -> usize {
+        self.pairs
+    }
+
+    // accumulate matching keypoints (x, y, angle) in image a and image b,
+    // skipping pairs where either can't be described
+    pub fn accumulate_pairs(&mut self, a:&GrayImage, b:&GrayImage,
+                            pairs:&[((u32, u32, f32), (u32, u32, f32))]) {
+        let kernel = self.trainer.kernel;
+        self.accumulate_prepared_pairs(&KernelImage::new(Cow::Borrowed(a), kernel),
+                                       &KernelImage::new(Cow::Borrowed(b), kernel), pairs);
+    }
+
+    // as accumulate_pairs, from images prepared with the trainer's kernel
+    pub fn accumulate_prepared_pairs(&mut self, a:&KernelImage, b:&KernelImage,
+                                     pairs:&[((u32, u32, f32), (u32, u32, f32))]) {
+        debug_assert_eq!((a.kernel(), b.kernel()), (self.trainer.kernel, self.trainer.kernel));
+        let r = self.trainer.kernel.radius() as i32;
+        let patch = |image:&KernelImage, (x, y, angle):(u32, u32, f32)|
+            image.patch(x, y).map(|p| (p, angle_bin(angle)));
+        let (patches_a, patches_b):(Vec<(Patch, usize)>, Vec<(Patch, usize)>) = maybe_par_iter!(pairs)
+            .filter_map(|&(pa, pb)| match (patch(a, pa), patch(b, pb)) {
+                (Some(pa), Some(pb)) => Some((pa, pb)),
+                _ => None
+            })
+            .unzip();
+        let rotated = &self.trainer.rotated;
+        for (batch_a, batch_b) in patches_a.chunks(64).zip(patches_b.chunks(64)) {
+            maybe_par_iter!(&mut self.trainer.scores)
+                .zip(maybe_par_iter!(&mut self.flips))
+                .enumerate()
+                .for_each(|(i, (score, flips))| {
+                    let word_a = test_word(rotated, batch_a, i, r);
+                    let word_b = test_word(rotated, batch_b, i, r);
+                    score.push_word(word_a, batch_a.len());
+                    *flips += (word_a ^ word_b).count_ones();
+                });
+            self.pairs += batch_a.len();
         }
     }
+
+    // the fraction of pairs each test flipped on, all zero before any pairs
+    pub fn flip_rates(&self) -> Vec<f32> {
+        let pairs = self.pairs.max(1) as f32;
+        self.flips.iter().map(|&f| f as f32 / pairs).collect()
+    }
+
+    pub fn make_test_set(&self) -> Result<(TestSet, TrainingReport)> {
+        self.make_test_set_with(&GreedyThreshold::default(), 0)
+    }
+
+    pub fn make_test_set_with(&self, selection:&dyn Selection, seed:u64) -> Result<(TestSet, TrainingReport)> {
+        // prefer tests that are stable and have a mean close to 0.5
+        let flip_rates = self.flip_rates();
+        let cost:Vec<f32> = self.trainer.scores.iter().zip(flip_rates.iter())
+            .map(|(s, f)| f + (0.5 - s.mean()).abs())
+            .collect();
+        let mut order:Vec<usize> = (0..cost.len()).collect();
+        order.sort_by_key(|&i| OrderedFloat(cost[i]));
+        self.trainer.select_tests(&order, selection, Some(&flip_rates), seed)
+    }
 }
 
 #[cfg(test)]
@@ -388,6 +1631,27 @@ mod tests {
          }
     }
 
+    #[test]
+    fn test_rbrief_patterns() {
+        for &pattern in [Pattern::Uniform, Pattern::Gaussian, Pattern::CentredGaussian,
+                         Pattern::PolarGrid, Pattern::CentredPolarGrid].iter() {
+            for seed in 0..20 {
+                let t = TestSet::from_pattern(pattern, seed);
+                assert_eq!(t.set.len(), 128);
+                assert!(t.set.iter().all(|p| p.valid() && p.0 != p.1));
+                for (i, p) in t.set.iter().enumerate() {
+                    assert!(t.set[..i].iter().all(|q| !(q.0 == p.0 && q.1 == p.1) && !(q.0 == p.1 && q.1 == p.0)),
+                            "{:?} seed {} repeats a pair", pattern, seed);
+                }
+            }
+            assert_eq!(TestSet::from_pattern(pattern, 42).set, TestSet::from_pattern(pattern, 42).set);
+        }
+        assert_eq!(TestSet::with_seed(7).set, TestSet::with_seed(7).set);
+        assert_ne!(TestSet::with_seed(7).set, TestSet::with_seed(8).set);
+        let t = TestSet::from_pattern(Pattern::CentredPolarGrid, 0);
+        assert!(t.set.iter().all(|p| p.0 == Point { x: 0, y: 0 }));
+    }
+
     #[test]
     fn test_rbrief_rotate() {
         let t = TestSet::new();
@@ -405,11 +1669,92 @@ mod tests {
         let r = RADIUS;
         let image = ImageBuffer::from_pixel(r * 2, r * 2, Luma([1u8]));
         let integral = integral_image::integral_image::<_, u32>(&image);
-        assert_eq!(sample(&integral, 
+        assert_eq!(sample(&Patch::Box(integral, 2),
                                   &Point{x: r as i32, y: r as i32},
                                   &Point{x: 0, y:0}), 25);
     }
 
+    #[test]
+    fn test_rbrief_kernel() {
+        assert_eq!(Kernel::default().radius(), RADIUS);
+        assert_eq!(Kernel::parse("box:7").unwrap(), Kernel::Box(7));
+        assert_eq!(Kernel::parse("gaussian:1.5").unwrap(), Kernel::Gaussian(1.5));
+        assert!(Kernel::parse("disc:3").is_err());
+        let image = ImageBuffer::from_pixel(RADIUS * 4, RADIUS * 4, Luma([3u8]));
+        let c = RADIUS * 2;
+        let kernel = Kernel::Box(7);
+        let patch = make_patch(&image, c, c, kernel).unwrap();
+        let r = kernel.radius() as i32;
+        assert_eq!(sample(&patch, &Point{x: r, y: r}, &Point{x: MAX, y: -MAX}), 3 * 49);
+        let kernel = Kernel::Gaussian(2.0);
+        let patch = make_patch(&image, c, c, kernel).unwrap();
+        let r = kernel.radius() as i32;
+        assert_eq!(sample(&patch, &Point{x: r, y: r}, &Point{x: MAX, y: -MAX}), 3 * 256);
+        assert!(make_patch(&image, kernel.radius() - 1, c, kernel).is_none());
+
+        // a level smoothed once describes as patches smoothed one by one do
+        let image = ImageBuffer::from_fn(RADIUS * 4, RADIUS * 4, |x, y| Luma([((x * 31 + y * y * 7) % 256) as u8]));
+        for &kernel in [Kernel::Box(5), Kernel::Gaussian(2.0)].iter() {
+            let rbrief = RBrief::from_test_set(TestSet { kernel: kernel, ..TestSet::with_seed(1) });
+            let level = KernelImage::new(Cow::Borrowed(&image), kernel);
+            for &(x, y, angle) in [(c, c, 0.3), (kernel.radius(), c + 5, 2.0)].iter() {
+                assert_eq!(rbrief.describe_prepared(&level, x, y, angle), rbrief.describe(&image, x, y, angle));
+            }
+            assert_eq!(rbrief.describe_upright_prepared(&level, 1, c), None);
+        }
+    }
+
+    #[test]
+    fn test_rbrief_save_load() {
+        let mut t = TestSet::new();
+        t.kernel = Kernel::Gaussian(1.5);
+        let filename = std::env::temp_dir().join("rbrief_test_save_load.json");
+        let filename = filename.to_str().unwrap();
+        t.save(filename).unwrap();
+        let loaded = TestSet::load(filename).unwrap();
+        assert_eq!(loaded.set, t.set);
+        assert_eq!(loaded.kernel, t.kernel);
+        // a bare list of pairs uses the default kernel
+        fs::write(filename, serde_json::to_string(&t.set).unwrap()).unwrap();
+        let loaded = TestSet::load(filename).unwrap();
+        assert_eq!(loaded.set, t.set);
+        assert_eq!(loaded.kernel, Kernel::default());
+        t.save_binary(filename).unwrap();
+        let loaded = TestSet::load(filename).unwrap();
+        assert_eq!(loaded.set, t.set);
+        assert_eq!(loaded.kernel, t.kernel);
+        assert_eq!(loaded.provenance, t.provenance);
+        fs::remove_file(filename).unwrap();
+    }
+
+    #[test]
+    fn test_rbrief_test_set_validation() {
+        let t = TestSet::trained();
+        assert_eq!(t.set.len(), 128);
+        assert_eq!(t.header().descriptor_bits, 128);
+        let json = t.to_json().unwrap();
+        assert!(TestSet::from_json(&json).is_ok());
+        assert!(TestSet::from_json(&json.replace("\"version\":1", "\"version\":99")).is_err());
+        assert!(TestSet::from_json(&json.replace("\"descriptor_bits\":128", "\"descriptor_bits\":64")).is_err());
+        let mut bytes = t.to_bytes();
+        assert!(TestSet::from_bytes(&bytes).is_ok());
+        assert!(TestSet::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        bytes.push(0);
+        assert!(TestSet::from_bytes(&bytes).is_err());
+        // huge counts of tests or provenance bytes are rejected before allocating
+        for &at in [13, 21].iter() {
+            let mut bytes = t.to_bytes();
+            bytes[at..at + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+            assert!(TestSet::from_bytes(&bytes).is_err());
+        }
+        let mut t = TestSet::with_seed(0);
+        t.set[0].0.x = MAX + 1;
+        assert!(t.validate().is_err());
+        let mut t = TestSet::with_seed(0);
+        t.kernel = Kernel::Box(4);
+        assert!(t.validate().is_err());
+    }
+
     #[test]
     fn test_rbrief_test() {
         let r = RADIUS;
@@ -420,9 +1765,29 @@ mod tests {
         let pair = PairPoint(
             Point { x: r as i32, y: r as i32 },
             Point { x: 3 * r as i32, y: r as i32 });
-        assert_eq!(test(&integral, &Point{x: 0, y: 0}, &pair), false);
+        let patch = Patch::Box(integral, 2);
+        assert_eq!(test(&patch, &Point{x: 0, y: 0}, &pair), false);
         let pair = PairPoint(pair.1, pair.0);
-        assert_eq!(test(&integral, &Point{x: 0, y: 0}, &pair), true);
+        assert_eq!(test(&patch, &Point{x: 0, y: 0}, &pair), true);
+    }
+
+    #[test]
+    fn test_rbrief_describe_upright() {
+        let r = RADIUS;
+        let mut image = GrayImage::new(r * 4, r * 4);
+        imageops::horizontal_gradient(&mut image, &Luma([0]), &Luma([255]));
+        let rbrief = RBrief::new();
+        let d = rbrief.describe_upright(&image, r * 2, r * 2);
+        assert!(d.is_some());
+        assert_eq!(d, rbrief.describe(&image, r * 2, r * 2, 0.0));
+        assert_eq!(rbrief.describe_upright(&image, 1, 1), None);
+
+        // a pruned set gives the low bits of its descriptor
+        let set = TestSet::with_seed(0);
+        let kept:Vec<usize> = (0..64).collect();
+        let full = RBrief::from_test_set(TestSet::with_seed(0)).describe_upright(&image, r * 2, r * 2);
+        let pruned = RBrief::from_test_set(set.subset(&kept)).describe_upright(&image, r * 2, r * 2);
+        assert_eq!(pruned, full.map(|d| d & (u64::MAX as u128)));
     }
 
     #[test]
@@ -464,6 +1829,170 @@ mod tests {
         }
         assert_eq!(b.correlation(&c), 0.5);
      }
+
+    #[test]
+    fn test_bit_vec_append() {
+        let bits = [true, false, true, true, false, false, true, false, true, true, true];
+        for split in 0..bits.len() {
+            let mut a = BitVec::new();
+            let mut b = BitVec::new();
+            let mut c = BitVec::new();
+            for (i, &bit) in bits.iter().enumerate() {
+                if i < split { a.push(bit) } else { b.push(bit) };
+                c.push(bit);
+            }
+            a.append(&b);
+            assert_eq!(a, c);
+            assert_eq!(BitVec::from_bytes(c.bytes().to_vec(), c.len()), c);
+        }
+    }
+
+    #[test]
+    fn test_trainer_batch() {
+        let r = RADIUS;
+        let mut image = GrayImage::new(r * 4, r * 4);
+        imageops::horizontal_gradient(&mut image, &Luma([0]), &Luma([255]));
+        let keypoints:Vec<(u32, u32, f32)> = (0..70)
+            .map(|i| (r * 2 + i % 7, r * 2 + i / 7, i as f32 * 0.1))
+            .collect();
+        let mut batched = Trainer::new();
+        batched.accumulate_batch(&image, &keypoints[..3]);
+        batched.accumulate_batch(&image, &keypoints[3..]);
+        let mut single = Trainer::new();
+        for &(x, y, angle) in keypoints.iter() {
+            single.accumulate(&image, x, y, angle);
+        }
+        assert_eq!(batched.samples(), 70);
+        assert!(batched.scores == single.scores);
+    }
+
+    #[test]
+    fn test_selection_limits() {
+        let mut scores = vec![BitVec::new(); 5];
+        for (i, s) in scores.iter_mut().enumerate() {
+            s.push_word(0x5a5a_1234_u64.rotate_left(i as u32 * 7), 64);
+        }
+        let candidates:Vec<Candidate> = scores.iter().map(|s| Candidate { scores: s }).collect();
+        let selections:Vec<Box<dyn Selection>> = vec![
+            Box::new(GreedyThreshold::default()),
+            Box::new(IncrementalGreedyThreshold::default()),
+            Box::new(MinMaxGreedy::default())
+        ];
+        for selection in selections.iter() {
+            assert!(selection.select(&[], 128).is_err());
+            assert!(selection.select(&candidates, 6).is_err());
+            // a single test used to lower the threshold forever
+            assert_eq!(selection.select(&candidates, 1).unwrap().0, vec![0]);
+            assert_eq!(selection.select(&candidates, 5).unwrap().0.len(), 5);
+        }
+        assert!(MinMaxGreedy { pool: 2 }.select(&candidates, 3).is_err());
+        assert!(GreedyThreshold { delta: 0.0, ..GreedyThreshold::default() }.select(&candidates, 3).is_err());
+        // no passes, or an even number ending on a lowered threshold, still collect count
+        for &iterations in [0, 4].iter() {
+            let search = GreedyThreshold { iterations: iterations, ..GreedyThreshold::default() };
+            assert_eq!(search.select(&candidates, 3).unwrap().0.len(), 3);
+            let incremental = IncrementalGreedyThreshold { search: search };
+            assert_eq!(incremental.select(&candidates, 3).unwrap().0.len(), 3);
+        }
+    }
+
+    #[test]
+    fn test_trainer_report() {
+        let mut rng = ChaCha8Rng::seed_from_u64(1);
+        let image = GrayImage::from_fn(RADIUS * 6, RADIUS * 6, |_, _| Luma([rng.gen::<u8>()]));
+        let keypoints:Vec<(u32, u32, f32)> = (0..64)
+            .map(|i| (RADIUS + (i % 32) * 2, RADIUS * 2 + (i / 32) * 4, 0.0))
+            .collect();
+        let mut trainer = Trainer::new();
+        assert!(matches!(trainer.make_test_set(), Err(Error::NoSamples)));
+        trainer.accumulate_batch(&image, &keypoints);
+        let (set, report) = trainer.make_test_set().unwrap();
+        assert_eq!(set.set.len(), 128);
+        assert_eq!(report.selection, "greedy");
+        assert_eq!(report.samples, 64);
+        assert_eq!(report.tests.len(), 128);
+        assert!(report.thresholds.iter().any(|t| t.collected == 128));
+        assert_le!(report.selected.mean_correlation, report.selected.max_correlation);
+        let json = serde_json::to_string(&report).unwrap();
+        assert_eq!(serde_json::from_str::<TrainingReport>(&json).unwrap(), report);
+        // a selection short of 128 tests is an error rather than a short descriptor
+        struct First;
+        impl Selection for First {
+            fn name(&self) -> String {
+                "first".to_string()
+            }
+
+            fn select(&self, _candidates:&[Candidate], _count:usize) -> Result<(Vec<usize>, Vec<ThresholdStep>)> {
+                Ok((vec![0], vec![]))
+            }
+        }
+        assert!(matches!(trainer.make_test_set_with(&First, 0), Err(Error::InvalidTestSet(_))));
+        // statistics of no values are zero rather than NaN so a report still loads
+        assert_eq!(mean_stddev(&[]), (0.0, 0.0));
+        let filename = std::env::temp_dir().join("image_processing_test_report.json");
+        let filename = filename.to_str().unwrap();
+        report.save(filename).unwrap();
+        assert_eq!(TrainingReport::load(filename).unwrap(), report);
+        fs::remove_file(filename).unwrap();
+
+        // caching correlations between passes mustn't change the result
+        let (incremental, incremental_report) =
+            trainer.make_test_set_with(&IncrementalGreedyThreshold::default(), 0).unwrap();
+        assert_eq!(incremental.set, set.set);
+        assert_eq!(incremental_report.thresholds, report.thresholds);
+
+        let (minmax, minmax_report) = trainer.make_test_set_with(&MinMaxGreedy { pool: 1000 }, 0).unwrap();
+        assert_eq!(minmax.set.len(), 128);
+        assert_eq!(minmax_report.thresholds.len(), 128);
+
+        let stats = trainer.test_statistics(&set);
+        for (s, t) in stats.iter().zip(report.tests.iter()) {
+            assert_eq!(s.as_ref().map(|s| s.mean), Some(t.mean));
+        }
+        let swapped = TestSet {
+            set: vec![PairPoint(set.set[0].1.clone(), set.set[0].0.clone()),
+                      PairPoint::from(0, 0, 1, 0)],
+            kernel: set.kernel,
+            provenance: String::new()
+        };
+        let stats = trainer.test_statistics(&swapped);
+        assert_eq!(stats[0].as_ref().map(|s| s.mean), Some(1.0 - report.tests[0].mean));
+        assert_eq!(stats[1], None);
+    }
+
+    #[test]
+    fn test_trainer_save_load_merge() {
+        let r = RADIUS;
+        let mut image = GrayImage::new(r * 4, r * 4);
+        imageops::horizontal_gradient(&mut image, &Luma([0]), &Luma([255]));
+        let mut a = Trainer::new();
+        a.accumulate(&image, r * 2, r * 2, 0.3);
+        let mut b = Trainer::new();
+        b.accumulate(&image, r * 2, r * 2, 1.2);
+        b.accumulate(&image, r * 2 + 3, r * 2, 2.0);
+        let mut all = Trainer::new();
+        all.accumulate(&image, r * 2, r * 2, 0.3);
+        all.accumulate(&image, r * 2, r * 2, 1.2);
+        all.accumulate(&image, r * 2 + 3, r * 2, 2.0);
+
+        let filename = std::env::temp_dir().join("rbrief_test_trainer.bin");
+        let filename = filename.to_str().unwrap();
+        b.save(filename).unwrap();
+        let saved = fs::read(filename).unwrap();
+        let mut huge = saved.clone();
+        huge[17..25].copy_from_slice(&u64::MAX.to_le_bytes());
+        fs::write(filename, huge).unwrap();
+        assert!(Trainer::load(filename).is_err());
+        fs::write(filename, saved).unwrap();
+        let b = Trainer::load(filename).unwrap();
+        fs::remove_file(filename).unwrap();
+        assert_eq!(b.samples(), 2);
+
+        a.merge(&b).unwrap();
+        assert_eq!(a.samples(), 3);
+        assert!(a.scores == all.scores);
+        assert!(a.merge(&Trainer::with_kernel(Kernel::Box(3))).is_err());
+    }
 }
 
 
@@ -0,0 +1,181 @@
+use rand::Rng;
+use rand::seq::index;
+use serde::{Serialize, Deserialize};
+
+// a pair of matching points, from one image to the other
+pub type PointPair = ((f32, f32), (f32, f32));
+
+// where the homography h takes x, y
+pub fn transform(h:&na::Matrix3<f64>, x:f32, y:f32) -> (f32, f32) {
+    let p = h * na::Vector3::new(x as f64, y as f64, 1.0);
+    ((p.x / p.z) as f32, (p.y / p.z) as f32)
+}
+
+// translate and scale points to have their centroid at the origin and mean
+// distance sqrt(2) from it, returning the transform that does this
+fn normalisation(points:&[(f32, f32)]) -> na::Matrix3<f64> {
+    let n = points.len() as f64;
+    let cx = points.iter().map(|p| p.0 as f64).sum::<f64>() / n;
+    let cy = points.iter().map(|p| p.1 as f64).sum::<f64>() / n;
+    let d = points.iter()
+        .map(|p| ((p.0 as f64 - cx).powi(2) + (p.1 as f64 - cy).powi(2)).sqrt())
+        .sum::<f64>() / n;
+    let s = if d > 0.0 { std::f64::consts::SQRT_2 / d } else { 1.0 };
+    na::Matrix3::new(s, 0.0, -s * cx, 0.0, s, -s * cy, 0.0, 0.0, 1.0)
+}
+
+// the homography taking the first point of each pair to the second, by the
+// normalised direct linear transform over four or more pairs
+pub fn homography(pairs:&[PointPair]) -> Option<na::Matrix3<f64>> {
+    if pairs.len() < 4 {
+        return None;
+    }
+    let from:Vec<(f32, f32)> = pairs.iter().map(|p| p.0).collect();
+    let to:Vec<(f32, f32)> = pairs.iter().map(|p| p.1).collect();
+    let tf = normalisation(&from);
+    let tt = normalisation(&to);
+    let mut ata = na::DMatrix::<f64>::zeros(9, 9);
+    for (a, b) in from.iter().zip(to.iter()) {
+        let a = tf * na::Vector3::new(a.0 as f64, a.1 as f64, 1.0);
+        let b = tt * na::Vector3::new(b.0 as f64, b.1 as f64, 1.0);
+        let rows = [
+            [-a.x, -a.y, -1.0, 0.0, 0.0, 0.0, b.x * a.x, b.x * a.y, b.x],
+            [0.0, 0.0, 0.0, -a.x, -a.y, -1.0, b.y * a.x, b.y * a.y, b.y]
+        ];
+        for r in rows.iter() {
+            for i in 0..9 {
+                for j in 0..9 {
+                    ata[(i, j)] += r[i] * r[j];
+                }
+            }
+        }
+    }
+    // the solution is the eigenvector of AtA with the smallest eigenvalue
+    let eigen = na::SymmetricEigen::new(ata);
+    let (smallest, _) = eigen.eigenvalues.iter().enumerate()
+        .fold((0, f64::MAX), |(bi, bv), (i, &v)| if v < bv { (i, v) } else { (bi, bv) });
+    let v = eigen.eigenvectors.column(smallest);
+    let h = na::Matrix3::new(v[0], v[1], v[2], v[3], v[4], v[5], v[6], v[7], v[8]);
+    let h = tt.try_inverse()? * h * tf;
+    if h[(2, 2)].abs() < 1e-12 {
+        return None;
+    }
+    Some(h / h[(2, 2)])
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RansacParams {
+    pub iterations: usize,
+    // the largest reprojection error in pixels of an inlier
+    pub threshold: f32,
+    pub min_inliers: usize
+}
+
+impl Default for RansacParams {
+    fn default() -> RansacParams {
+        RansacParams {
+            iterations: 1000,
+            threshold: 3.0,
+            min_inliers: 8
+        }
+    }
+}
+
+// a homography between two images and the indices of the pairs that agree with it
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Registration {
+    pub homography: [[f64; 3]; 3],
+    pub inliers: Vec<usize>
+}
+
+impl Registration {
+    pub fn matrix(&self) -> na::Matrix3<f64> {
+        let h = &self.homography;
+        na::Matrix3::new(h[0][0], h[0][1], h[0][2],
+                         h[1][0], h[1][1], h[1][2],
+                         h[2][0], h[2][1], h[2][2])
+    }
+}
+
+fn inliers(h:&na::Matrix3<f64>, pairs:&[PointPair], threshold:f32) -> Vec<usize> {
+    pairs.iter().enumerate()
+        .filter(|(_i, (a, b))| {
+            let (x, y) = transform(h, a.0, a.1);
+            let (dx, dy) = (x - b.0, y - b.1);
+            (dx * dx + dy * dy).sqrt() <= threshold
+        })
+        .map(|(i, _p)| i)
+        .collect()
+}
+
+// fit a homography to the pairs with RANSAC, refining it over the inliers of the best fit
+pub fn register<R:Rng>(pairs:&[PointPair], params:&RansacParams, rng:&mut R) -> Option<Registration> {
+    if pairs.len() < 4 {
+        return None;
+    }
+    let mut best:Option<(na::Matrix3<f64>, Vec<usize>)> = None;
+    for _i in 0..params.iterations {
+        let sample:Vec<PointPair> = index::sample(rng, pairs.len(), 4)
+            .iter()
+            .map(|i| pairs[i])
+            .collect();
+        if let Some(h) = homography(&sample) {
+            let found = inliers(&h, pairs, params.threshold);
+            if best.as_ref().map_or(true, |b| found.len() > b.1.len()) {
+                best = Some((h, found));
+            }
+        }
+    }
+    let (h, found) = best?;
+    if found.len() < params.min_inliers.max(4) {
+        return None;
+    }
+    let inlying:Vec<PointPair> = found.iter().map(|&i| pairs[i]).collect();
+    // keep the fit over all the inliers only if it hasn't lost support
+    let (h, found) = match homography(&inlying) {
+        Some(refined) => {
+            let refined_found = inliers(&refined, pairs, params.threshold);
+            if refined_found.len() >= found.len() { (refined, refined_found) } else { (h, found) }
+        },
+        None => (h, found)
+    };
+    Some(Registration {
+        homography: [[h[(0, 0)], h[(0, 1)], h[(0, 2)]],
+                     [h[(1, 0)], h[(1, 1)], h[(1, 2)]],
+                     [h[(2, 0)], h[(2, 1)], h[(2, 2)]]],
+        inliers: found
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    #[test]
+    fn test_register() {
+        let h = na::Matrix3::new(0.9, -0.2, 30.0, 0.25, 1.1, -12.0, 0.0002, -0.0001, 1.0);
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut pairs:Vec<PointPair> = (0..60)
+            .map(|_| {
+                let p = (rng.gen_range(0.0, 640.0), rng.gen_range(0.0, 480.0));
+                (p, transform(&h, p.0, p.1))
+            })
+            .collect();
+        for i in 0..20 {
+            pairs[i * 3].1 = (rng.gen_range(0.0, 640.0), rng.gen_range(0.0, 480.0));
+        }
+        let exact = homography(&pairs[1..3].iter().chain(pairs[4..6].iter()).cloned().collect::<Vec<_>>())
+            .unwrap();
+        assert!((exact - h).abs().max() < 1e-3);
+
+        let r = register(&pairs, &RansacParams::default(), &mut rng).unwrap();
+        assert_eq!(r.inliers.len(), 40);
+        assert!(r.inliers.iter().all(|i| i % 3 != 0));
+        let (x, y) = transform(&r.matrix(), 100.0, 200.0);
+        let (ex, ey) = transform(&h, 100.0, 200.0);
+        assert!((x - ex).abs() < 0.1 && (y - ey).abs() < 0.1);
+        assert_eq!(register(&pairs[..3], &RansacParams::default(), &mut rng), None);
+    }
+}
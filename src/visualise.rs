@@ -0,0 +1,161 @@
+use image::{Rgba, RgbaImage};
+use imageproc::drawing;
+use imageproc::rect::Rect;
+use std::fs;
+use crate::rbrief::{PairStatistics, Point, TestSet, Trainer};
+use crate::error::{Error, Result};
+
+// how the tests of a rendered set are coloured
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Colouring {
+    Plain,
+    // the fraction of training keypoints for which the test was true
+    Mean,
+    // the largest correlation with another test of the set
+    Correlation
+}
+
+const BACKGROUND:Rgba<u8> = Rgba([255u8, 255u8, 255u8, 255u8]);
+const OUTLINE:Rgba<u8> = Rgba([192u8, 192u8, 192u8, 255u8]);
+const UNKNOWN:Rgba<u8> = Rgba([128u8, 128u8, 128u8, 255u8]);
+
+// blue for 0 through to red for 1
+fn colour_map(v:f32) -> Rgba<u8> {
+    let v = num::clamp(v, 0.0, 1.0);
+    Rgba([(255.0 * v) as u8, 0u8, (255.0 * (1.0 - v)) as u8, 255u8])
+}
+
+// a colour for each test of set, from the statistics gathered by trainer, which
+// every colouring but Plain needs. Correlations are stretched over the range
+// found in the set.
+pub fn test_colours(set:&TestSet, trainer:Option<&Trainer>, colouring:Colouring) -> Result<Vec<Rgba<u8>>> {
+    let stats:Vec<Option<PairStatistics>> = match (trainer, colouring) {
+        (_, Colouring::Plain) => return Ok(vec![colour_map(0.0); set.set.len()]),
+        (Some(trainer), _) => trainer.test_statistics(set),
+        (None, _) => return Err(Error::InvalidConfig(format!("{:?} colouring needs a trainer", colouring)))
+    };
+    let values:Vec<Option<f32>> = stats.iter()
+        .map(|s| s.as_ref().map(|s| match colouring {
+            Colouring::Mean => s.mean,
+            _ => s.max_correlation
+        }))
+        .collect();
+    let (low, high) = match colouring {
+        Colouring::Mean => (0.0, 1.0),
+        _ => values.iter().filter_map(|&v| v)
+            .fold((1.0f32, 0.0f32), |(l, h), v| (l.min(v), h.max(v)))
+    };
+    Ok(values.iter()
+        .map(|v| match v {
+            Some(v) if high > low => colour_map((v - low) / (high - low)),
+            Some(_v) => colour_map(0.0),
+            None => UNKNOWN
+        })
+        .collect())
+}
+
+// the drawing extents of the set's patch, in patch pixels either side of the centre
+fn extent(set:&TestSet) -> i32 {
+    set.kernel.radius() as i32
+}
+
+fn to_pixel(set:&TestSet, p:&Point, scale:u32) -> (f32, f32) {
+    let e = extent(set);
+    let s = scale as f32;
+    ((p.x + e) as f32 * s + s / 2.0, (p.y + e) as f32 * s + s / 2.0)
+}
+
+// draw each test as a line between the two sample boxes it compares, with
+// each patch pixel scale pixels wide
+pub fn render(set:&TestSet, colours:&[Rgba<u8>], scale:u32) -> RgbaImage {
+    let e = extent(set);
+    let size = (2 * e + 1) as u32 * scale;
+    let mut image = RgbaImage::from_pixel(size, size, BACKGROUND);
+    let half = crate::rbrief::HWIDTH as i32;
+    drawing::draw_hollow_rect_mut(&mut image,
+        Rect::at((e - half) * scale as i32, (e - half) * scale as i32)
+            .of_size((2 * half + 1) as u32 * scale, (2 * half + 1) as u32 * scale),
+        OUTLINE);
+    let margin = set.kernel.margin() as i32;
+    let box_size = (2 * margin + 1) as u32 * scale;
+    for (pair, &colour) in set.set.iter().zip(colours.iter()) {
+        for p in [&pair.0, &pair.1].iter() {
+            let corner = Rect::at((p.x - margin + e) * scale as i32, (p.y - margin + e) * scale as i32)
+                .of_size(box_size, box_size);
+            drawing::draw_hollow_rect_mut(&mut image, corner, colour);
+        }
+        drawing::draw_line_segment_mut(&mut image,
+            to_pixel(set, &pair.0, scale), to_pixel(set, &pair.1, scale), colour);
+    }
+    image
+}
+
+pub fn render_svg(set:&TestSet, colours:&[Rgba<u8>], scale:u32) -> String {
+    let e = extent(set);
+    let size = (2 * e + 1) as u32 * scale;
+    let half = crate::rbrief::HWIDTH as i32;
+    let hex = |c:&Rgba<u8>| format!("#{:02x}{:02x}{:02x}", c[0], c[1], c[2]);
+    let mut svg = format!("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{0}\" height=\"{0}\" \
+                           viewBox=\"0 0 {0} {0}\">\n", size);
+    svg += &format!("<rect width=\"{0}\" height=\"{0}\" fill=\"{1}\"/>\n", size, hex(&BACKGROUND));
+    let outline = (e - half) * scale as i32;
+    svg += &format!("<rect x=\"{0}\" y=\"{0}\" width=\"{1}\" height=\"{1}\" fill=\"none\" stroke=\"{2}\"/>\n",
+                    outline, (2 * half + 1) as u32 * scale, hex(&OUTLINE));
+    let margin = set.kernel.margin() as i32;
+    let box_size = (2 * margin + 1) as u32 * scale;
+    for (pair, colour) in set.set.iter().zip(colours.iter()) {
+        let c = hex(colour);
+        for p in [&pair.0, &pair.1].iter() {
+            svg += &format!("<rect x=\"{}\" y=\"{}\" width=\"{2}\" height=\"{2}\" fill=\"none\" stroke=\"{3}\"/>\n",
+                            (p.x - margin + e) * scale as i32, (p.y - margin + e) * scale as i32,
+                            box_size, c);
+        }
+        let (x0, y0) = to_pixel(set, &pair.0, scale);
+        let (x1, y1) = to_pixel(set, &pair.1, scale);
+        svg += &format!("<line x1=\"{}\" y1=\"{}\" x2=\"{}\" y2=\"{}\" stroke=\"{}\"/>\n",
+                        x0, y0, x1, y1, c);
+    }
+    svg += "</svg>\n";
+    svg
+}
+
+// write the rendered set as SVG if the filename ends in .svg, otherwise as an image
+pub fn save(set:&TestSet, colours:&[Rgba<u8>], scale:u32, filename:&str) -> Result<()> {
+    if filename.to_lowercase().ends_with(".svg") {
+        fs::write(filename, render_svg(set, colours, scale))?;
+    } else {
+        render(set, colours, scale).save(filename)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rbrief::{Kernel, RBrief};
+
+    #[test]
+    fn test_render() {
+        let set = TestSet::trained();
+        assert!(test_colours(&set, None, Colouring::Mean).is_err());
+        let colours = test_colours(&set, None, Colouring::Plain).unwrap();
+        assert_eq!(colours.len(), 128);
+        let image = render(&set, &colours, 4);
+        let size = (2 * Kernel::default().radius() + 1) * 4;
+        assert_eq!(image.dimensions(), (size, size));
+        assert!(image.pixels().any(|p| *p == colour_map(0.0)));
+        let svg = render_svg(&set, &colours, 4);
+        assert_eq!(svg.matches("<line").count(), 128);
+
+        // rotated sets stay within the drawing
+        let rbrief = RBrief::from_test_set(set);
+        let rotated = rbrief.test_set(std::f32::consts::PI / 4.0);
+        let size = render(rotated, &colours, 1).width() as f32;
+        for pair in rotated.set.iter() {
+            for p in [&pair.0, &pair.1].iter() {
+                let (x, y) = to_pixel(rotated, p, 1);
+                assert!(x >= 0.0 && x < size && y >= 0.0 && y < size, "{:?} is outside the drawing", p);
+            }
+        }
+    }
+}